@@ -0,0 +1,109 @@
+//! Times `AssemblerInterpreter::interpret` on scaled up versions of the
+//! factorial and Fibonacci programs of `simple_test`. Run with `cargo bench`.
+//!
+//! Each bench prints a reference time per run, measured in release mode
+//! when the run loop was last optimized, to compare with. With
+//! `BENCH_CHECK=1` set, the bench fails if a run is more than `TOLERANCE`
+//! times slower. The references only hold on a comparable machine; update
+//! them along with a deliberate change of speed.
+
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use simple_assembler_code_wars::AssemblerInterpreter;
+
+const FACTORIAL: &str = "
+mov   n, 20000          ; repetitions
+loop:
+    mov   a, 20
+    mov   b, a
+    mov   c, a
+    call  proc_fact
+    dec   n
+    cmp   n, 0
+    jne   loop
+call  print
+end
+
+proc_fact:
+    dec   b
+    mul   c, b
+    cmp   b, 1
+    jne   proc_fact
+    ret
+
+print:
+    msg   a, '! = ', c
+    ret
+";
+
+const FIBONACCI: &str = "
+mov   n, 20000          ; repetitions
+loop:
+    mov   a, 90         ; value
+    mov   b, 0          ; next
+    mov   c, 0          ; counter
+    mov   d, 0          ; first
+    mov   e, 1          ; second
+    call  proc_fib
+    dec   n
+    cmp   n, 0
+    jne   loop
+call  print
+end
+
+proc_fib:
+    cmp   c, 2
+    jl    func_0
+    mov   b, d
+    add   b, e
+    mov   d, e
+    mov   e, b
+    inc   c
+    cmp   c, a
+    jle   proc_fib
+    ret
+
+func_0:
+    mov   b, c
+    inc   c
+    jmp   proc_fib
+
+print:
+    msg   'Term ', a, ' of Fibonacci series is: ', b
+    ret
+";
+
+const TOLERANCE: u32 = 2;
+
+/// Returns whether the time per run is within the tolerance of
+/// `reference`, printing it too when `check` is set.
+fn bench(name: &str, program: &str, expected: &str, reference: Duration, check: bool) -> bool {
+    let runs = 5;
+    let start = Instant::now();
+    for _ in 0..runs {
+        assert_eq!(AssemblerInterpreter::interpret(program).as_deref(), Some(expected));
+    }
+    let time = start.elapsed() / runs;
+    let ok = time <= reference * TOLERANCE;
+    let verdict = if !check { "" } else if ok { "  ok" } else { "  TOO SLOW" };
+    println!("{:<10} {:>10.2?} / run (reference {:.2?}){}", name, time, reference, verdict);
+    ok
+}
+
+fn main() -> ExitCode {
+    let check = std::env::var_os("BENCH_CHECK").is_some_and(|check| check == "1");
+    let factorial = bench("factorial", FACTORIAL, "20! = 2432902008176640000", Duration::from_millis(8), check);
+    let fibonacci = bench(
+        "fibonacci",
+        FIBONACCI,
+        "Term 90 of Fibonacci series is: 2880067194370816120",
+        Duration::from_millis(65),
+        check,
+    );
+    if !check || factorial && fibonacci {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
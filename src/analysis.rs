@@ -0,0 +1,84 @@
+use crate::decode::{Decoded, Instr, Operand};
+
+/// Definite-assignment analysis: finds the reads of a register that may
+/// happen before any write to it, as `(address, register index)` pairs.
+///
+/// The flow is context-insensitive: a `ret` may go back after any `call`,
+/// and a `jnz` by a register may go anywhere. Unreachable code is ignored.
+pub fn uninitialized_reads(program: &Decoded) -> Vec<(usize, usize)> {
+    let n = program.instrs.len();
+    let returns: Vec<usize> = (0..n).filter(|&a| matches!(program.instrs[a], Instr::Call(_))).map(|a| a + 1).collect();
+    let successors = |address: usize| -> Vec<usize> {
+        let next = address + 1;
+        let mut succ = match program.instrs[address] {
+            Instr::End => Vec::new(),
+            Instr::Jmp(t) | Instr::Call(t) => vec![t],
+            Instr::Jne(t) | Instr::Je(t) | Instr::Jge(t) | Instr::Jg(t) | Instr::Jle(t) | Instr::Jl(t) => vec![next, t],
+            Instr::Jnz(_, Operand::Val(offset)) => {
+                let target = (address as i64).checked_add(offset).and_then(|a| usize::try_from(a).ok());
+                std::iter::once(next).chain(target).collect()
+            }
+            Instr::Jnz(_, Operand::Reg(_)) => (0..n).collect(),
+            Instr::Ret => returns.clone(),
+            _ => vec![next],
+        };
+        succ.retain(|&a| a < n);
+        succ
+    };
+
+    // registers surely written when reaching each address, None if unreached
+    let mut written: Vec<Option<Vec<bool>>> = vec![None; n];
+    if n > 0 {
+        written[0] = Some(vec![false; program.reg_names.len()]);
+    }
+    let mut work = vec![0];
+    while let Some(address) = work.pop() {
+        let Some(mut out) = written[address].clone() else { continue };
+        if let Some(r) = program.instrs[address].written() {
+            out[r] = true;
+        }
+        for next in successors(address) {
+            let merged = match &written[next] {
+                None => out.clone(),
+                Some(cur) => cur.iter().zip(&out).map(|(a, b)| *a && *b).collect(),
+            };
+            if written[next].as_ref() != Some(&merged) {
+                written[next] = Some(merged);
+                work.push(next);
+            }
+        }
+    }
+
+    let mut reads = Vec::new();
+    for (address, state) in written.iter().enumerate() {
+        let Some(state) = state else { continue };
+        program.reads(program.instrs[address], |r| {
+            if !state[r] && !reads.contains(&(address, r)) {
+                reads.push((address, r));
+            }
+        });
+    }
+    reads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Program;
+
+    fn reads(source: &str) -> Vec<(usize, String)> {
+        let program = &Program::load(source).unwrap().decoded;
+        uninitialized_reads(program).into_iter().map(|(a, r)| (a, program.reg_names[r].clone())).collect()
+    }
+
+    #[test]
+    fn test_uninitialized_reads() {
+        assert_eq!(reads("mov a, 1\nadd a, b\nmsg a, b\nend"), vec![(1, "b".to_string()), (2, "b".to_string())]);
+        // c is written on one path only
+        assert_eq!(reads("mov a, 1\ncmp a, 0\nje skip\nmov c, 2\nskip:\nmsg c\nend"), vec![(5, "c".to_string())]);
+        // a subroutine initialising registers for its caller
+        assert_eq!(reads("mov a, 5\ncall init\nmsg a, b\nend\ninit:\nmov b, a\nret"), vec![]);
+        // loops back with everything written
+        assert_eq!(reads("mov a, 3\nloop:\nmov b, a\ndec a\njnz a, -2\nmsg b\nend\nmsg x\nend"), vec![]);
+    }
+}
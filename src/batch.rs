@@ -0,0 +1,98 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{Cpu, ExecutionReport, Program};
+
+/// One execution of a batch: a program and the initial value of some of
+/// its registers.
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub program: Arc<Program>,
+    pub inputs: Vec<(String, i64)>,
+}
+
+/// Outcome of a `Job`. `report` is an error if `configure` failed or an
+/// input names a register the program doesn't use.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobResult {
+    pub report: Result<ExecutionReport, String>,
+    pub elapsed: Duration,
+}
+
+/// Runs `jobs` on `threads` threads (0 for one per available core) and
+/// returns their results in the order of `jobs`. `configure` is called on
+/// the cpu of each job before its inputs are set, e.g. to set a budget or
+/// a deadline.
+pub fn run_batch(
+    jobs: &[Job],
+    threads: usize,
+    configure: impl Fn(&mut Cpu) -> Result<(), String> + Sync,
+) -> Vec<JobResult> {
+    let threads = match threads {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<JobResult>>> = Mutex::new(vec![None; jobs.len()]);
+    thread::scope(|s| {
+        for _ in 0..threads.min(jobs.len()) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(job) = jobs.get(i) else { break };
+                let result = run_job(job, &configure);
+                results.lock().unwrap_or_else(|e| e.into_inner())[i] = Some(result);
+            });
+        }
+    });
+    results.into_inner().unwrap_or_else(|e| e.into_inner()).into_iter().map(|r| r.expect("job not run")).collect()
+}
+
+fn run_job(job: &Job, configure: &impl Fn(&mut Cpu) -> Result<(), String>) -> JobResult {
+    let start = Instant::now();
+    let mut cpu = job.program.cpu();
+    let report = configure(&mut cpu)
+        .and_then(|_| job.inputs.iter().try_for_each(|(name, val)| cpu.set_register_value(name, *val)))
+        .map(|_| cpu.execute());
+    JobResult { report, elapsed: start.elapsed() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RunError, Termination};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_run_batch() {
+        assert_send_sync::<Program>();
+        assert_send_sync::<Job>();
+
+        let double = Arc::new(Program::load("add a, a\nmsg a\nend").unwrap());
+        let forever = Arc::new(Program::load("loop:\njmp loop\nend").unwrap());
+        let job = |program: &Arc<Program>, inputs: &[(&str, i64)]| Job {
+            program: program.clone(),
+            inputs: inputs.iter().map(|(name, val)| (name.to_string(), *val)).collect(),
+        };
+        let mut jobs: Vec<Job> = (0..50).map(|a| job(&double, &[("a", a)])).collect();
+        jobs.push(job(&forever, &[]));
+        jobs.push(job(&double, &[("b", 1)]));
+
+        let results = run_batch(&jobs, 4, |cpu| {
+            cpu.set_max_steps(Some(1000));
+            Ok(())
+        });
+        assert_eq!(results.len(), 52);
+        for (a, result) in results[..50].iter().enumerate() {
+            assert_eq!(result.report.clone().unwrap().into_result(), Some((2 * a).to_string()));
+        }
+        let report = results[50].report.as_ref().unwrap();
+        assert!(matches!(report.termination, Termination::Failed(RunError::BudgetExhausted { steps: 1000, .. })));
+        assert!(results[51].report.is_err());
+
+        let results = run_batch(&jobs[..1], 1, |cpu| cpu.set_fault_handler(Some("on_fault")));
+        assert_eq!(results[0].report, Err("unknown fault handler label `on_fault`".to_string()));
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+use serde_json::{json, Value};
+
+use crate::framing::{read_message, write_message};
+use crate::{Breakpoint, Cpu, RunError, StepOutcome, DEFAULT_CHECKPOINT_INTERVAL};
+
+/// The only thread of a debugged program.
+const THREAD_ID: i64 = 1;
+/// `variablesReference` of the registers scope.
+const REGISTERS_REF: i64 = 1;
+
+/// Debug Adapter Protocol server debugging one assembly program, see
+/// `serve_dap`.
+///
+/// The `launch` request takes the path of the program in `program`, and
+/// optionally `stopOnEntry` and the initial `registers` as an object of
+/// names to values. Execution happens while a request is handled, so a
+/// program that never stops blocks the server.
+pub struct DapServer<W: Write> {
+    out: W,
+    seq: i64,
+    cpu: Option<Cpu>,
+    path: String,
+    breakpoint_lines: Vec<usize>,
+    stop_on_entry: bool,
+    sent: usize,     // bytes of the msg output already sent
+    faulted: bool,   // stopped on an error, the next resume terminates
+}
+
+/// Serves DAP requests read from `input` until `disconnect` or the end of
+/// the input.
+pub fn serve_dap(mut input: impl BufRead, output: impl Write) -> io::Result<()> {
+    let mut server = DapServer::new(output);
+    while let Some(request) = read_message(&mut input)? {
+        match request {
+            Ok(request) => {
+                if !server.handle(&request)? {
+                    break;
+                }
+            }
+            Err(e) => server.parse_error(&e)?,
+        }
+    }
+    Ok(())
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new(out: W) -> Self {
+        DapServer {
+            out,
+            seq: 0,
+            cpu: None,
+            path: String::new(),
+            breakpoint_lines: Vec::new(),
+            stop_on_entry: false,
+            sent: 0,
+            faulted: false,
+        }
+    }
+
+    /// Handles one request. Returns false once the client disconnected.
+    pub fn handle(&mut self, request: &Value) -> io::Result<bool> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+        match command {
+            "initialize" => {
+                self.respond(request, Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsSetVariable": true,
+                    "supportsEvaluateForHovers": true,
+                    "supportsStepBack": true,
+                })))?;
+                self.event("initialized", json!({}))?;
+            }
+            "launch" => {
+                let result = self.launch(args);
+                self.respond(request, result.map(|_| json!({})))?;
+            }
+            "setBreakpoints" => {
+                self.breakpoint_lines = args["breakpoints"]
+                    .as_array()
+                    .map(|bps| bps.iter().filter_map(|bp| bp["line"].as_u64()).map(|l| l as usize).collect())
+                    .unwrap_or_default();
+                let breakpoints = self.apply_breakpoints();
+                self.respond(request, Ok(json!({ "breakpoints": breakpoints })))?;
+            }
+            "setExceptionBreakpoints" => self.respond(request, Ok(json!({})))?,
+            "configurationDone" => {
+                self.respond(request, Ok(json!({})))?;
+                if let Some(cpu) = &self.cpu {
+                    if self.stop_on_entry {
+                        self.stopped("entry", None)?;
+                    } else if cpu.breakpoints().any(|address| address == cpu.ip()) {
+                        // `resume` would run the first instruction before checking the breakpoints
+                        self.stopped("breakpoint", None)?;
+                    } else {
+                        self.resume(Cpu::resume, "breakpoint")?;
+                    }
+                }
+            }
+            "threads" => self.respond(request, Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })))?,
+            "stackTrace" => {
+                let frames = self.stack_frames();
+                self.respond(request, Ok(json!({ "stackFrames": frames, "totalFrames": frames.len() })))?;
+            }
+            "scopes" => self.respond(request, Ok(json!({
+                "scopes": [{ "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false }]
+            })))?,
+            "variables" => {
+                let variables: Vec<Value> = match &self.cpu {
+                    Some(cpu) if args["variablesReference"] == REGISTERS_REF => cpu
+                        .registers()
+                        .map(|(name, val)| json!({ "name": name, "value": val.to_string(), "variablesReference": 0 }))
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                self.respond(request, Ok(json!({ "variables": variables })))?;
+            }
+            "setVariable" => {
+                let result = self.set_variable(args["name"].as_str().unwrap_or_default(), &args["value"]);
+                self.respond(request, result.map(|val| json!({ "value": val.to_string() })))?;
+            }
+            "evaluate" => {
+                let name = args["expression"].as_str().unwrap_or_default().trim();
+                let result = match self.cpu.as_ref().and_then(|cpu| cpu.get_register_value(name)) {
+                    Some(val) => Ok(json!({ "result": val.to_string(), "variablesReference": 0 })),
+                    None => Err(format!("unknown register `{}`", name)),
+                };
+                self.respond(request, result)?;
+            }
+            "continue" => {
+                self.respond(request, Ok(json!({ "allThreadsContinued": true })))?;
+                self.resume(Cpu::resume, "breakpoint")?;
+            }
+            "next" => {
+                self.respond(request, Ok(json!({})))?;
+                self.resume(Cpu::step_over, "step")?;
+            }
+            "stepIn" => {
+                self.respond(request, Ok(json!({})))?;
+                self.resume(Cpu::step, "step")?;
+            }
+            "stepOut" => {
+                self.respond(request, Ok(json!({})))?;
+                self.resume(Cpu::step_out, "step")?;
+            }
+            "stepBack" | "reverseContinue" => {
+                self.respond(request, Ok(json!({})))?;
+                self.faulted = false; // going back leaves the error behind
+                match command {
+                    "stepBack" => self.resume(Cpu::reverse_step, "step")?,
+                    _ => self.resume(Cpu::reverse_continue, "breakpoint")?,
+                }
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(json!({})))?;
+                return Ok(false);
+            }
+            _ => self.respond(request, Err(format!("unsupported request `{}`", command)))?,
+        }
+        Ok(true)
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<(), String> {
+        let path = args["program"].as_str().ok_or("missing `program`")?;
+        let source = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut cpu = Cpu::new();
+        cpu.set_checkpoint_interval(Some(DEFAULT_CHECKPOINT_INTERVAL));
+        if let Err(diagnostics) = cpu.load_code(&source) {
+            let errors: Vec<String> = diagnostics.iter().filter(|d| d.is_error()).map(|d| d.to_string()).collect();
+            return Err(errors.join("\n"));
+        }
+        if let Some(registers) = args["registers"].as_object() {
+            for (name, val) in registers {
+                let val = val.as_i64().ok_or_else(|| format!("bad value for register {}", name))?;
+                cpu.set_register_value(name, val)?;
+            }
+        }
+        self.cpu = Some(cpu);
+        self.path = path.to_string();
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.apply_breakpoints();
+        Ok(())
+    }
+
+    /// Replaces the breakpoints of the cpu with `breakpoint_lines`,
+    /// returning them as DAP breakpoints.
+    fn apply_breakpoints(&mut self) -> Vec<Value> {
+        let Some(cpu) = &mut self.cpu else {
+            return self.breakpoint_lines.iter().map(|line| json!({ "verified": false, "line": line })).collect();
+        };
+        let old: BTreeSet<usize> = cpu.breakpoints().collect();
+        for address in old {
+            cpu.remove_breakpoint(address);
+        }
+        let mut breakpoints = Vec::new();
+        for &line in &self.breakpoint_lines {
+            match cpu.set_breakpoint(&Breakpoint::Line(line)) {
+                Ok(address) => {
+                    let line = cpu.source_pos(address).map_or(line, |pos| pos.line);
+                    breakpoints.push(json!({ "verified": true, "line": line }));
+                }
+                Err(e) => breakpoints.push(json!({ "verified": false, "line": line, "message": e })),
+            }
+        }
+        breakpoints
+    }
+
+    fn stack_frames(&self) -> Vec<Value> {
+        let Some(cpu) = &self.cpu else { return Vec::new() };
+        std::iter::once(cpu.ip())
+            .chain(cpu.call_stack().iter().rev().copied())
+            .enumerate()
+            .map(|(id, address)| {
+                let frame = cpu.frame(address);
+                json!({
+                    "id": id,
+                    "name": frame.label.unwrap_or_else(|| "main".to_string()),
+                    "source": { "path": self.path },
+                    "line": frame.line.unwrap_or(0),
+                    "column": 1,
+                    "instructionPointerReference": address.to_string(),
+                })
+            })
+            .collect()
+    }
+
+    fn set_variable(&mut self, name: &str, value: &Value) -> Result<i64, String> {
+        let cpu = self.cpu.as_mut().ok_or("no program")?;
+        let val = match value {
+            Value::String(s) => s.trim().parse().map_err(|_| format!("bad value `{}`", s))?,
+            v => v.as_i64().ok_or_else(|| format!("bad value `{}`", v))?,
+        };
+        cpu.set_register_value(name, val)?;
+        Ok(val)
+    }
+
+    /// Moves the cpu with `how`, then reports the output and where it
+    /// stopped.
+    fn resume(&mut self, how: fn(&mut Cpu) -> Result<StepOutcome, RunError>, reason: &str) -> io::Result<()> {
+        let Some(cpu) = &mut self.cpu else { return Ok(()) };
+        if self.faulted {
+            return self.exit(1);
+        }
+        let result = how(cpu);
+        self.sent = self.sent.min(cpu.output().len()); // less output after going back
+        let printed = cpu.output()[self.sent..].to_string();
+        self.sent += printed.len();
+        if !printed.is_empty() {
+            self.output("stdout", &printed)?;
+        }
+        match result {
+            Ok(StepOutcome::Stepped) => self.stopped(reason, None),
+            Ok(StepOutcome::Breakpoint(_)) => self.stopped("breakpoint", None),
+            Ok(StepOutcome::End) => self.exit(0),
+            Ok(StepOutcome::NoEnd) | Err(RunError::NoEnd) => {
+                self.output("stderr", &format!("{}\n", RunError::NoEnd))?;
+                self.exit(1)
+            }
+            Err(e) => {
+                self.output("stderr", &format!("{}\n", e))?;
+                self.faulted = true;
+                self.stopped("exception", Some(e.to_string()))
+            }
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> io::Result<()> {
+        self.event("stopped", json!({ "reason": reason, "threadId": THREAD_ID, "text": text, "allThreadsStopped": true }))
+    }
+
+    fn exit(&mut self, code: i64) -> io::Result<()> {
+        self.event("exited", json!({ "exitCode": code }))?;
+        self.event("terminated", json!({}))
+    }
+
+    fn output(&mut self, category: &str, text: &str) -> io::Result<()> {
+        self.event("output", json!({ "category": category, "output": text }))
+    }
+
+    /// Answers a request that isn't valid JSON, whose seq and command are
+    /// unknown.
+    pub fn parse_error(&mut self, error: &str) -> io::Result<()> {
+        self.respond(&json!({ "seq": 0, "command": "" }), Err(format!("invalid JSON: {}", error)))
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        write_message(&mut self.out, &message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::tests::{read_all, requests};
+
+    #[test]
+    fn test_dap_session() {
+        let path = std::env::temp_dir().join(format!("dap_session_{}.asm", std::process::id()));
+        std::fs::write(&path, "mov a, 2\ncall double\nmsg 'a = ', a\nend\ndouble:\n  add a, a\n  ret\n").unwrap();
+        let input = requests(&[
+            json!({ "command": "initialize", "arguments": {} }),
+            json!({ "command": "launch", "arguments": { "program": path, "registers": {} } }),
+            json!({ "command": "setBreakpoints", "arguments": { "source": { "path": path }, "breakpoints": [{ "line": 6 }, { "line": 99 }] } }),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "variables", "arguments": { "variablesReference": 1 } }),
+            json!({ "command": "setVariable", "arguments": { "variablesReference": 1, "name": "a", "value": "10" } }),
+            json!({ "command": "stepOut", "arguments": { "threadId": 1 } }),
+            json!({ "command": "stepBack", "arguments": { "threadId": 1 } }),
+            json!({ "command": "stepBack", "arguments": { "threadId": 1 } }),
+            json!({ "command": "evaluate", "arguments": { "expression": "a" } }),
+            json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+            json!({ "command": "disconnect" }),
+        ]);
+        let mut out = Vec::new();
+        serve_dap(&input[..], &mut out).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let messages = read_all(&out);
+        let summary: Vec<String> = messages
+            .iter()
+            .map(|m| match m["type"].as_str().unwrap() {
+                "response" => format!("{} {}", m["command"].as_str().unwrap(), m["success"]),
+                _ => format!("event {}", m["event"].as_str().unwrap()),
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                "initialize true",
+                "event initialized",
+                "launch true",
+                "setBreakpoints true",
+                "configurationDone true",
+                "event stopped",
+                "stackTrace true",
+                "variables true",
+                "setVariable true",
+                "stepOut true",
+                "event stopped",
+                "stepBack true",
+                "event stopped",
+                "stepBack true",
+                "event stopped",
+                "evaluate true",
+                "continue true",
+                "event output",
+                "event exited",
+                "event terminated",
+                "disconnect true",
+            ]
+        );
+        assert_eq!(messages[3]["body"]["breakpoints"], json!([{ "verified": true, "line": 6 }, { "verified": false, "line": 99, "message": "No code on or after line 99" }]));
+        assert_eq!(messages[5]["body"]["reason"], "breakpoint");
+        let frames = &messages[6]["body"]["stackFrames"];
+        assert_eq!((&frames[0]["name"], &frames[0]["line"]), (&json!("double"), &json!(6)));
+        assert_eq!((&frames[1]["name"], &frames[1]["line"]), (&json!("main"), &json!(2)));
+        assert_eq!(messages[7]["body"]["variables"], json!([{ "name": "a", "value": "2", "variablesReference": 0 }]));
+        assert_eq!(messages[15]["body"]["result"], "10");
+        assert_eq!(messages[17]["body"]["output"], "a = 20");
+        assert_eq!(messages[18]["body"]["exitCode"], 0);
+    }
+
+    #[test]
+    fn test_dap_first_line_breakpoint() {
+        let path = std::env::temp_dir().join(format!("dap_first_line_{}.asm", std::process::id()));
+        std::fs::write(&path, "mov a, 1\ninc a\nend\n").unwrap();
+        let input = requests(&[
+            json!({ "command": "launch", "arguments": { "program": path } }),
+            json!({ "command": "setBreakpoints", "arguments": { "source": { "path": path }, "breakpoints": [{ "line": 1 }] } }),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+        ]);
+        let mut out = Vec::new();
+        serve_dap(&input[..], &mut out).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let events: Vec<Value> = read_all(&out).into_iter().filter(|m| m["type"] == "event").collect();
+        assert_eq!(events[0]["event"], "stopped");
+        assert_eq!(events[0]["body"]["reason"], "breakpoint");
+        assert_eq!(events.last().unwrap()["event"], "terminated");
+    }
+
+    #[test]
+    fn test_dap_parse_error() {
+        let mut input = b"Content-Length: 1\r\n\r\n{".to_vec();
+        input.extend(requests(&[json!({ "command": "initialize", "arguments": {} })]));
+        let mut out = Vec::new();
+        serve_dap(&input[..], &mut out).unwrap();
+        let messages = read_all(&out);
+        assert_eq!(messages[0]["success"], false);
+        assert!(messages[0]["message"].as_str().unwrap().starts_with("invalid JSON"));
+        assert_eq!(messages[1]["command"], "initialize");
+        assert_eq!(messages[1]["success"], true);
+    }
+}
@@ -0,0 +1,275 @@
+use std::io::{self, BufRead, Write};
+
+use crate::{Breakpoint, Cpu, RunError, StepOutcome, DEFAULT_CHECKPOINT_INTERVAL};
+
+const HELP: &str = "commands:
+  break <label|line>   stop before the first instruction of a label or a source line
+  delete <address>     remove a breakpoint
+  step                 execute one instruction
+  next                 execute one instruction, running a call until it returns
+  finish               run until the current subroutine returns
+  continue             run until a breakpoint or the end of the program
+  rstep                go back one instruction
+  rcontinue            go back to the previous breakpoint, or the start
+  print [reg]          show a register, or all of them
+  set <reg> <value>    change a register
+  bt                   show the pending calls
+  list                 show the code around the current instruction
+  quit                 leave the debugger";
+
+/// Number of instructions shown by `list` before and after the current one.
+const LIST_CONTEXT: usize = 5;
+
+/// A gdb-like prompt driving a loaded `Cpu`. Commands can be abbreviated
+/// to their first letter, `bt`, `rstep` (`rs`) and `rcontinue` (`rc`)
+/// excepted.
+pub struct Debugger {
+    cpu: Cpu,
+    started: bool, // the cpu was moved by a command
+    finished: bool,
+}
+
+impl Debugger {
+    /// Records the execution of `cpu` with the default checkpoint interval,
+    /// for the reverse commands.
+    pub fn new(mut cpu: Cpu) -> Self {
+        cpu.set_checkpoint_interval(Some(DEFAULT_CHECKPOINT_INTERVAL));
+        Debugger { cpu, started: false, finished: false }
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    /// Reads commands from `input` until `quit` or the end of the input.
+    pub fn run(&mut self, input: impl BufRead, mut out: impl Write) -> io::Result<()> {
+        write!(out, "(asm) ")?;
+        out.flush()?;
+        for line in input.lines() {
+            if !self.command(&line?, &mut out)? {
+                return Ok(());
+            }
+            write!(out, "(asm) ")?;
+            out.flush()?;
+        }
+        writeln!(out)
+    }
+
+    /// Executes one command line. Returns false on `quit`.
+    pub fn command(&mut self, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            ["q" | "quit"] => return Ok(false),
+            ["h" | "help"] => writeln!(out, "{}", HELP)?,
+            ["b" | "break", target] => {
+                let breakpoint = match target.parse() {
+                    Ok(line) => Breakpoint::Line(line),
+                    Err(_) => Breakpoint::Label(target.to_string()),
+                };
+                match self.cpu.set_breakpoint(&breakpoint) {
+                    Ok(address) => writeln!(out, "breakpoint at {}", self.location(address))?,
+                    Err(e) => writeln!(out, "error: {}", e)?,
+                }
+            }
+            ["d" | "delete", address] => match address.parse() {
+                Ok(address) if self.cpu.remove_breakpoint(address) => writeln!(out, "deleted breakpoint {}", address)?,
+                _ => writeln!(out, "error: no breakpoint at `{}`", address)?,
+            },
+            ["s" | "step"] => self.resume(out, Cpu::step)?,
+            ["n" | "next"] => self.resume(out, Cpu::step_over)?,
+            ["f" | "finish"] if self.cpu.call_stack().is_empty() => {
+                writeln!(out, "error: not in a subroutine")?
+            }
+            ["f" | "finish"] => self.resume(out, Cpu::step_out)?,
+            // `resume` runs the instruction under `ip`, a breakpoint on the first one is hit here
+            ["c" | "continue"] if !self.started && self.cpu.breakpoints().any(|address| address == self.cpu.ip()) => {
+                self.started = true;
+                writeln!(out, "breakpoint, {}", self.location(self.cpu.ip()))?
+            }
+            ["c" | "continue"] => self.resume(out, Cpu::resume)?,
+            ["rs" | "rstep"] => {
+                self.finished = false;
+                self.resume(out, Cpu::reverse_step)?
+            }
+            ["rc" | "rcontinue"] => {
+                self.finished = false;
+                self.resume(out, Cpu::reverse_continue)?
+            }
+            ["p" | "print"] => {
+                let regs: Vec<String> = self.cpu.registers().map(|(name, val)| format!("{} = {}", name, val)).collect();
+                writeln!(out, "{}", regs.join(", "))?
+            }
+            ["p" | "print", reg] => match self.cpu.get_register_value(reg) {
+                Some(val) => writeln!(out, "{} = {}", reg, val)?,
+                None => writeln!(out, "error: register {} is not used by the program", reg)?,
+            },
+            ["set", reg, value] => match value.parse() {
+                Ok(val) => match self.cpu.set_register_value(reg, val) {
+                    Ok(()) => writeln!(out, "{} = {}", reg, val)?,
+                    Err(e) => writeln!(out, "error: {}", e)?,
+                },
+                Err(_) => writeln!(out, "error: bad value `{}`", value)?,
+            },
+            ["bt"] => {
+                writeln!(out, "#0  {}", self.location(self.cpu.ip()))?;
+                for (i, frame) in self.cpu.backtrace().iter().enumerate() {
+                    writeln!(out, "#{}  {}", i + 1, self.location(frame.address))?;
+                }
+            }
+            ["l" | "list"] => self.list(out)?,
+            _ => writeln!(out, "error: unknown command `{}`, try `help`", line.trim())?,
+        }
+        Ok(true)
+    }
+
+    /// Moves the cpu with `how` and reports where it stopped.
+    fn resume(&mut self, out: &mut impl Write, how: fn(&mut Cpu) -> Result<StepOutcome, RunError>) -> io::Result<()> {
+        if self.finished {
+            return writeln!(out, "the program has finished");
+        }
+        self.started = true;
+        let before = self.cpu.output().len();
+        let result = how(&mut self.cpu);
+        let printed = self.cpu.output().get(before..).unwrap_or_default();
+        if !printed.is_empty() {
+            writeln!(out, "output: {}", printed)?;
+        }
+        match result {
+            Ok(StepOutcome::Stepped) => writeln!(out, "{}", self.location(self.cpu.ip())),
+            Ok(StepOutcome::Breakpoint(address)) => writeln!(out, "breakpoint, {}", self.location(address)),
+            Ok(StepOutcome::End) => {
+                self.finished = true;
+                writeln!(out, "program ended, output: {}", self.cpu.output())
+            }
+            Ok(StepOutcome::NoEnd) | Err(RunError::NoEnd) => {
+                self.finished = true;
+                writeln!(out, "error: {}", RunError::NoEnd)
+            }
+            Err(e) => {
+                self.finished = true;
+                writeln!(out, "error: {}", e)
+            }
+        }
+    }
+
+    /// `address 4 in f, line 5: inc a`
+    fn location(&self, address: usize) -> String {
+        let mut s = format!("address {}", address);
+        let frame = self.cpu.frame(address);
+        if let Some(label) = frame.label {
+            s += &format!(" in {}", label);
+        }
+        if let Some(line) = frame.line {
+            s += &format!(", line {}", line);
+        }
+        if let Some(command) = self.cpu.code().get(address) {
+            s += &format!(": {}", command);
+        }
+        s
+    }
+
+    fn list(&self, out: &mut impl Write) -> io::Result<()> {
+        let ip = self.cpu.ip();
+        let code = self.cpu.code();
+        let breakpoints: Vec<usize> = self.cpu.breakpoints().collect();
+        let first = ip.saturating_sub(LIST_CONTEXT);
+        for (address, command) in code.iter().enumerate().skip(first).take(ip + LIST_CONTEXT + 1 - first) {
+            let marker = if address == ip { "=>" } else { "  " };
+            let stop = if breakpoints.contains(&address) { "*" } else { " " };
+            let line = self.cpu.source_pos(address).map_or(0, |pos| pos.line);
+            writeln!(out, "{}{} {:>4} {:>4}  {}", marker, stop, address, line, command)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(source: &str, commands: &str) -> String {
+        let mut cpu = Cpu::new();
+        cpu.load_code(source).unwrap();
+        let mut out = Vec::new();
+        Debugger::new(cpu).run(commands.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap().replace("(asm) ", "")
+    }
+
+    #[test]
+    fn test_debugger_session() {
+        let source = "mov a, 2\ncall double\nmsg 'a = ', a\nend\ndouble:\n  add a, a\n  ret";
+        let out = session(source, "break double\ncontinue\nbt\nprint a\nset a 10\nfinish\nnext\nnext\nstep\nquit\n");
+        assert_eq!(
+            out.lines().collect::<Vec<_>>(),
+            vec![
+                "breakpoint at address 4 in double, line 5: double:",
+                "breakpoint, address 4 in double, line 5: double:",
+                "#0  address 4 in double, line 5: double:",
+                "#1  address 1, line 2: call double",
+                "a = 2",
+                "a = 10",
+                "address 2, line 3: msg 'a = ', a",
+                "output: a = 20",
+                "address 3, line 4: end",
+                "program ended, output: a = 20",
+                "the program has finished",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_debugger_reverse() {
+        let source = "mov a, 3\nloop:\n  dec a\n  jnz a, -1\nmsg 'done'\nend";
+        let out = session(source, "break 3\ncontinue\ncontinue\nprint a\nrstep\nprint a\nrcontinue\nprint a\nrcontinue\nrcontinue\nrstep\n");
+        assert_eq!(
+            out.lines().collect::<Vec<_>>(),
+            vec![
+                "breakpoint at address 2 in loop, line 3: dec a",
+                "breakpoint, address 2 in loop, line 3: dec a",
+                "breakpoint, address 2 in loop, line 3: dec a",
+                "a = 2",
+                "address 3 in loop, line 4: jnz a, -1",
+                "a = 2",
+                "breakpoint, address 2 in loop, line 3: dec a",
+                "a = 3",
+                "address 0, line 1: mov a, 3",
+                "address 0, line 1: mov a, 3",
+                "address 0, line 1: mov a, 3",
+                "",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_debugger_first_instruction_breakpoint() {
+        let out = session("mov a, 1\ninc a\nend", "break 1\ncontinue\ncontinue\n");
+        assert_eq!(
+            out.lines().collect::<Vec<_>>(),
+            vec![
+                "breakpoint at address 0, line 1: mov a, 1",
+                "breakpoint, address 0, line 1: mov a, 1",
+                "program ended, output: ",
+                "",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_debugger_list_and_errors() {
+        let out = session("mov a, 1\ninc a\nend", "break 2\nlist\nfinish\nprint b\nfoo\n");
+        assert_eq!(
+            out.lines().collect::<Vec<_>>(),
+            vec![
+                "breakpoint at address 1, line 2: inc a",
+                "=>     0    1  mov a, 1",
+                "  *    1    2  inc a",
+                "       2    3  end",
+                "error: not in a subroutine",
+                "error: register b is not used by the program",
+                "error: unknown command `foo`, try `help`",
+                "",
+            ]
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use std::fmt::Write;
+
+use crate::{Command, MsgArg, Param, Target};
+
+/// Operand of a decoded instruction: an immediate value or a register index.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Val(i64),
+    Reg(usize),
+}
+
+/// Pre-decoded form of a `Command`, executed by `Cpu::run`. Registers are
+/// indexes into `Cpu::regs`, jumps carry their resolved address and msg
+/// refers to its parts in `Decoded::msgs`, so the instruction is `Copy` and
+/// the dispatch loop never allocates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instr {
+    Nop, // label
+    Mov(usize, Operand),
+    Inc(usize),
+    Dec(usize),
+    Jnz(Operand, Operand),
+    Add(usize, Operand),
+    Sub(usize, Operand),
+    Mul(usize, Operand),
+    Div(usize, Operand),
+    Cmp(Operand, Operand),
+    Jmp(usize),
+    Jne(usize),
+    Je(usize),
+    Jge(usize),
+    Jg(usize),
+    Jle(usize),
+    Jl(usize),
+    Call(usize),
+    Ret,
+    Msg(usize),
+    End,
+}
+
+impl Instr {
+    /// The register the instruction writes, if any.
+    pub fn written(self) -> Option<usize> {
+        match self {
+            Instr::Mov(r, _)
+            | Instr::Inc(r)
+            | Instr::Dec(r)
+            | Instr::Add(r, _)
+            | Instr::Sub(r, _)
+            | Instr::Mul(r, _)
+            | Instr::Div(r, _) => Some(r),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MsgPart {
+    Text(String),
+    Reg(usize),
+}
+
+/// A linked program ready to run.
+#[derive(Debug, Clone, Default)]
+pub struct Decoded {
+    pub instrs: Vec<Instr>,
+    pub msgs: Vec<Vec<MsgPart>>,
+    pub reg_names: Vec<String>,
+}
+
+impl Decoded {
+    /// Index of a register, if the program uses it.
+    pub fn reg_index(&self, name: &str) -> Option<usize> {
+        self.reg_names.iter().position(|r| r == name)
+    }
+
+    fn reg(&mut self, name: &str) -> usize {
+        self.reg_index(name).unwrap_or_else(|| {
+            self.reg_names.push(name.to_string());
+            self.reg_names.len() - 1
+        })
+    }
+
+    fn operand(&mut self, p: &Param) -> Operand {
+        match p {
+            Param::Val(v) => Operand::Val(*v),
+            Param::Register(r) => Operand::Reg(self.reg(r)),
+        }
+    }
+
+    fn msg(&mut self, args: &[MsgArg]) -> usize {
+        let mut parts = Vec::new();
+        let mut s = String::new();
+        for arg in args {
+            match arg {
+                MsgArg::Text(text) => s.push_str(text),
+                MsgArg::Val(val) => {
+                    let _ = write!(s, "{}", val);
+                }
+                MsgArg::Register(r) => {
+                    if !s.is_empty() {
+                        parts.push(MsgPart::Text(std::mem::take(&mut s)));
+                    }
+                    parts.push(MsgPart::Reg(self.reg(r)));
+                }
+            }
+        }
+        if !s.is_empty() {
+            parts.push(MsgPart::Text(s));
+        }
+        self.msgs.push(parts);
+        self.msgs.len() - 1
+    }
+
+    /// Calls `f` with every register `instr` reads, msg parts included.
+    pub fn reads(&self, instr: Instr, mut f: impl FnMut(usize)) {
+        let mut operand = |op: Operand| {
+            if let Operand::Reg(r) = op {
+                f(r)
+            }
+        };
+        match instr {
+            Instr::Mov(_, op) => operand(op),
+            Instr::Inc(r) | Instr::Dec(r) => operand(Operand::Reg(r)),
+            Instr::Add(r, op) | Instr::Sub(r, op) | Instr::Mul(r, op) | Instr::Div(r, op) => {
+                operand(Operand::Reg(r));
+                operand(op);
+            }
+            Instr::Jnz(a, b) | Instr::Cmp(a, b) => {
+                operand(a);
+                operand(b);
+            }
+            Instr::Msg(m) => {
+                for part in &self.msgs[m] {
+                    if let MsgPart::Reg(r) = part {
+                        operand(Operand::Reg(*r));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Writes the output of the msg instruction `index` to `out`.
+    pub fn write_msg(&self, index: usize, regs: &[crate::Register], out: &mut String) {
+        for part in &self.msgs[index] {
+            match part {
+                MsgPart::Text(s) => out.push_str(s),
+                MsgPart::Reg(r) => {
+                    let _ = write!(out, "{}", regs[*r].get_value());
+                }
+            }
+        }
+    }
+}
+
+/// Decodes linked code. Fails on a jump whose label was never resolved.
+pub fn decode(code: &[Command]) -> Result<Decoded, String> {
+    let mut d = Decoded::default();
+    let addr = |t: &Target| t.address.ok_or_else(|| format!("Unresolved label {}", t.label));
+    for command in code {
+        let instr = match command {
+            Command::Move(r, p) => Instr::Mov(d.reg(r), d.operand(p)),
+            Command::Inc(r) => Instr::Inc(d.reg(r)),
+            Command::Dec(r) => Instr::Dec(d.reg(r)),
+            Command::Jnz(a, b) => Instr::Jnz(d.operand(a), d.operand(b)),
+            Command::Add(r, p) => Instr::Add(d.reg(r), d.operand(p)),
+            Command::Sub(r, p) => Instr::Sub(d.reg(r), d.operand(p)),
+            Command::Mul(r, p) => Instr::Mul(d.reg(r), d.operand(p)),
+            Command::Div(r, p) => Instr::Div(d.reg(r), d.operand(p)),
+            Command::Cmp(a, b) => Instr::Cmp(d.operand(a), d.operand(b)),
+            Command::Label(_) | Command::Comment => Instr::Nop,
+            Command::Jmp(t) => Instr::Jmp(addr(t)?),
+            Command::Jne(t) => Instr::Jne(addr(t)?),
+            Command::Je(t) => Instr::Je(addr(t)?),
+            Command::Jge(t) => Instr::Jge(addr(t)?),
+            Command::Jg(t) => Instr::Jg(addr(t)?),
+            Command::Jle(t) => Instr::Jle(addr(t)?),
+            Command::Jl(t) => Instr::Jl(addr(t)?),
+            Command::Call(t) => Instr::Call(addr(t)?),
+            Command::Ret => Instr::Ret,
+            Command::Msg(args) => Instr::Msg(d.msg(args)),
+            Command::End => Instr::End,
+        };
+        d.instrs.push(instr);
+    }
+    Ok(d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let code = vec![
+            Command::Move("b".to_string(), Param::Val(2)),
+            Command::Label("l".to_string()),
+            Command::Add("a".to_string(), Param::Register("b".to_string())),
+            Command::Jmp(Target { label: "l".to_string(), address: Some(1) }),
+            Command::Msg(vec![
+                MsgArg::Text("a = ".to_string()),
+                MsgArg::Register("a".to_string()),
+                MsgArg::Text(" ; ".to_string()),
+                MsgArg::Val(3),
+                MsgArg::Register("b".to_string()),
+            ]),
+        ];
+        let d = decode(&code).unwrap();
+        assert_eq!(d.reg_names, vec!["b", "a"]);
+        assert_eq!(d.instrs[..4], [Instr::Mov(0, Operand::Val(2)), Instr::Nop, Instr::Add(1, Operand::Reg(0)), Instr::Jmp(1)]);
+        assert_eq!(d.msgs[0], vec![MsgPart::Text("a = ".to_string()), MsgPart::Reg(1), MsgPart::Text(" ; 3".to_string()), MsgPart::Reg(0)]);
+
+        assert!(decode(&[Command::Call(Target::new("nowhere"))]).is_err());
+    }
+}
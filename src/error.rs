@@ -0,0 +1,265 @@
+use std::fmt;
+
+use crate::lexer::Span;
+
+/// Location of a problem in the source program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourcePos {
+    pub line: usize,     // 1-based line number
+    pub column: usize,   // 1-based column (in chars) of the offending token
+    pub snippet: String, // the source line, trimmed
+}
+
+impl SourcePos {
+    pub fn new(span: Span, line: &str) -> Self {
+        SourcePos { line: span.line, column: span.column, snippet: line.trim().to_string() }
+    }
+}
+
+impl fmt::Display for SourcePos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: `{}`", self.line, self.column, self.snippet)
+    }
+}
+
+/// Problem found while loading a program, see `Diagnostic` for its severity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmError {
+    InvalidToken { pos: SourcePos, token: String },
+    UnterminatedString { pos: SourcePos },
+    UnexpectedToken { pos: SourcePos, found: String, expected: &'static str },
+    UnknownInstruction { pos: SourcePos, mnemonic: String },
+    OperandCount { pos: SourcePos, mnemonic: String, expected: usize, found: usize },
+    BadOperand { pos: SourcePos, operand: String, expected: &'static str },
+    UndeclaredRegister { pos: SourcePos, name: String },
+    DuplicateLabel { pos: SourcePos, name: String, first_line: usize },
+    UndefinedLabel { pos: SourcePos, name: String },
+    UnusedLabel { pos: SourcePos, name: String },
+    UninitializedRead { pos: SourcePos, name: String },
+    MissingEnd { pos: SourcePos },
+}
+
+impl AsmError {
+    pub fn pos(&self) -> &SourcePos {
+        match self {
+            AsmError::InvalidToken { pos, .. }
+            | AsmError::UnterminatedString { pos }
+            | AsmError::UnexpectedToken { pos, .. }
+            | AsmError::UnknownInstruction { pos, .. }
+            | AsmError::OperandCount { pos, .. }
+            | AsmError::BadOperand { pos, .. }
+            | AsmError::UndeclaredRegister { pos, .. }
+            | AsmError::DuplicateLabel { pos, .. }
+            | AsmError::UndefinedLabel { pos, .. }
+            | AsmError::UnusedLabel { pos, .. }
+            | AsmError::UninitializedRead { pos, .. }
+            | AsmError::MissingEnd { pos } => pos,
+        }
+    }
+
+    /// The description of the problem, without its position.
+    pub fn message(&self) -> String {
+        match self {
+            AsmError::InvalidToken { token, .. } => format!("invalid token `{}`", token),
+            AsmError::UnterminatedString { .. } => "unterminated string".to_string(),
+            AsmError::UnexpectedToken { found, expected, .. } => format!("expected {}, found `{}`", expected, found),
+            AsmError::UnknownInstruction { mnemonic, .. } => format!("unknown instruction `{}`", mnemonic),
+            AsmError::OperandCount { mnemonic, expected, found, .. } => {
+                format!("`{}` expects {} operand(s), found {}", mnemonic, expected, found)
+            }
+            AsmError::BadOperand { operand, expected, .. } => format!("bad operand `{}`, expected {}", operand, expected),
+            AsmError::UndeclaredRegister { name, .. } => format!("undeclared register `{}`", name),
+            AsmError::DuplicateLabel { name, first_line, .. } => {
+                format!("label `{}` already defined on line {}", name, first_line)
+            }
+            AsmError::UndefinedLabel { name, .. } => format!("undefined label `{}`", name),
+            AsmError::UnusedLabel { name, .. } => format!("label `{}` is never used", name),
+            AsmError::UninitializedRead { name, .. } => format!("register `{}` may be read before it is written", name),
+            AsmError::MissingEnd { .. } => "program has no `end`, it can't succeed".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.message(), self.pos())
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// An `AsmError` reported while loading, with its severity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub error: AsmError,
+}
+
+impl Diagnostic {
+    pub fn error(error: AsmError) -> Self {
+        Diagnostic { severity: Severity::Error, error }
+    }
+
+    pub fn warning(error: AsmError) -> Self {
+        Diagnostic { severity: Severity::Warning, error }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Error => write!(f, "error: {}", self.error),
+            Severity::Warning => write!(f, "warning: {}", self.error),
+        }
+    }
+}
+
+/// Why `Cpu::run` (or a stepping function) stopped without reaching `end`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunError {
+    NoEnd,                                      // ran past the last instruction
+    BudgetExhausted { ip: usize, steps: u64 },  // see `Cpu::set_max_steps`
+    Cancelled { ip: usize, steps: u64, output: String },  // see `CancelToken`
+    TimedOut { ip: usize, steps: u64, output: String },   // see `Cpu::set_deadline`
+    InvalidBreakpoint(String),
+    NoHistory,                                  // see `Cpu::set_checkpoint_interval`
+    Fault(Box<RuntimeError>),                   // the program did something invalid
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::NoEnd => write!(f, "program ended without `end`"),
+            RunError::BudgetExhausted { ip, steps } => {
+                write!(f, "instruction budget exhausted after {} steps at address {}", steps, ip)
+            }
+            RunError::Cancelled { ip, steps, .. } => write!(f, "cancelled after {} steps at address {}", steps, ip),
+            RunError::TimedOut { ip, steps, .. } => write!(f, "timed out after {} steps at address {}", steps, ip),
+            RunError::InvalidBreakpoint(e) => write!(f, "invalid breakpoint: {}", e),
+            RunError::NoHistory => write!(f, "the execution is not recorded, it can't go back"),
+            RunError::Fault(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FaultKind {
+    DivisionByZero,
+    DivisionOverflow,       // i64::MIN / -1 in checked mode
+    Overflow,               // arithmetic overflow in checked mode
+    BadJump { offset: i64 }, // jnz leaving the code
+    ReturnWithoutCall,      // ret on an empty call stack, see `Cpu::set_empty_return`
+    CallDepthExceeded,      // see `Cpu::set_max_call_depth`
+    UninitializedRegister { name: String }, // see `Cpu::set_uninitialized_reads`
+}
+
+impl fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FaultKind::DivisionByZero => write!(f, "division by zero"),
+            FaultKind::DivisionOverflow => write!(f, "division overflow"),
+            FaultKind::Overflow => write!(f, "arithmetic overflow"),
+            FaultKind::BadJump { offset } => write!(f, "jump by {} leaves the code", offset),
+            FaultKind::ReturnWithoutCall => write!(f, "ret without a pending call"),
+            FaultKind::CallDepthExceeded => write!(f, "maximum call depth exceeded"),
+            FaultKind::UninitializedRegister { name } => write!(f, "read of uninitialized register `{}`", name),
+        }
+    }
+}
+
+/// A fault raised by an instruction, with the state of the cpu at that
+/// point.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+    pub kind: FaultKind,
+    pub address: usize,
+    pub label: Option<String>,        // subroutine of the faulting instruction
+    pub pos: Option<SourcePos>,       // source of the faulting instruction
+    pub registers: Vec<(String, i64)>,
+    pub backtrace: Vec<Frame>,        // pending calls, innermost first
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at address {}", self.kind, self.address)?;
+        if let Some(label) = &self.label {
+            write!(f, " in {}", label)?;
+        }
+        if let Some(pos) = &self.pos {
+            write!(f, ", line {}: `{}`", pos.line, pos.snippet)?;
+        }
+        let regs: Vec<String> = self.registers.iter().map(|(name, val)| format!("{}={}", name, val)).collect();
+        write!(f, " [{}]", regs.join(" "))?;
+        if !self.backtrace.is_empty() {
+            write!(f, "\n{}", Backtrace(&self.backtrace))?;
+        }
+        Ok(())
+    }
+}
+
+/// Lines of a backtrace shown before the rest is summed up.
+const MAX_SHOWN_FRAMES: usize = 20;
+
+/// Displays frames one per line, indented, for a human: repeated frames
+/// (a recursion) are shown once with their count, and only the innermost
+/// ones are shown.
+pub struct Backtrace<'a>(pub &'a [Frame]);
+
+impl fmt::Display for Backtrace<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rest = self.0;
+        let mut lines = 0;
+        while let Some(frame) = rest.first() {
+            if lines == MAX_SHOWN_FRAMES {
+                return write!(f, "\n    ... {} more frames", rest.len());
+            }
+            let repeats = rest.iter().take_while(|&other| other == frame).count();
+            if lines > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "    {}", frame)?;
+            if repeats > 1 {
+                write!(f, " ({} times)", repeats)?;
+            }
+            rest = &rest[repeats..];
+            lines += 1;
+        }
+        Ok(())
+    }
+}
+
+/// A pending `call`: its address, the label of the subroutine it is in and
+/// its source line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub address: usize,
+    pub label: Option<String>,
+    pub line: Option<usize>,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "called from address {}", self.address)?;
+        if let Some(label) = &self.label {
+            write!(f, " in {}", label)?;
+        }
+        if let Some(line) = self.line {
+            write!(f, ", line {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
@@ -0,0 +1,76 @@
+use std::io::{self, BufRead, Write};
+
+use serde_json::Value;
+
+/// Reads one `Content-Length` framed JSON message, as used by the debug
+/// adapter and language server protocols. `None` at the end of the input,
+/// `Some(Err(..))` for a message that isn't valid JSON: the next message
+/// can still be read after it, unlike after an `io::Error`.
+pub fn read_message(input: &mut impl BufRead) -> io::Result<Option<Result<Value, String>>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body).map_err(|e| e.to_string())))
+}
+
+pub fn write_message(out: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Frames requests for a test session, numbering them from 1.
+    pub fn requests(messages: &[Value]) -> Vec<u8> {
+        let mut input = Vec::new();
+        for (seq, message) in messages.iter().enumerate() {
+            let mut message = message.clone();
+            message["seq"] = json!(seq + 1);
+            message["type"] = json!("request");
+            write_message(&mut input, &message).unwrap();
+        }
+        input
+    }
+
+    pub fn read_all(mut output: &[u8]) -> Vec<Value> {
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut output).unwrap() {
+            messages.push(message.unwrap());
+        }
+        messages
+    }
+
+    #[test]
+    fn test_framing() {
+        let input = requests(&[json!({ "command": "a" }), json!({ "command": "b", "arguments": { "x": "é" } })]);
+        let messages = read_all(&input);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1]["arguments"]["x"], "é");
+        assert_eq!(messages[1]["seq"], 2);
+        assert!(read_message(&mut &b"Content-Type: x\r\n\r\n{}"[..]).is_err());
+
+        let mut input = &b"Content-Length: 2\r\n\r\n{]Content-Length: 2\r\n\r\n{}"[..];
+        assert!(read_message(&mut input).unwrap().unwrap().is_err());
+        assert_eq!(read_message(&mut input).unwrap().unwrap(), Ok(json!({})));
+    }
+}
@@ -0,0 +1,155 @@
+use crate::error::{AsmError, SourcePos};
+
+/// Position of a token: 1-based line and column, length in chars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    Ident(String),   // mnemonic, register or label name
+    Int(i64),        // 42, -1, +3
+    Str(String),     // 'text', without the quotes
+    Comma,           // ,
+    Colon,           // :
+    Comment(String), // ; up to the end of the line
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+impl TokenKind {
+    /// How the token is shown in error messages.
+    pub fn describe(&self) -> String {
+        match self {
+            TokenKind::Ident(name) => name.clone(),
+            TokenKind::Int(val) => val.to_string(),
+            TokenKind::Str(text) => format!("'{}'", text),
+            TokenKind::Comma => ",".to_string(),
+            TokenKind::Colon => ":".to_string(),
+            TokenKind::Comment(_) => ";".to_string(),
+        }
+    }
+}
+
+/// Splits one source line into tokens.
+pub fn tokenize(line_no: usize, line: &str) -> Result<Vec<Token>, AsmError> {
+    let chars: Vec<char> = line.chars().collect();
+    let span = |start: usize, end: usize| Span { line: line_no, column: start + 1, len: end - start };
+    let text = |start: usize, end: usize| chars[start..end].iter().collect::<String>();
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let c = chars[i];
+        let kind = match c {
+            _ if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ',' => {
+                i += 1;
+                TokenKind::Comma
+            }
+            ':' => {
+                i += 1;
+                TokenKind::Colon
+            }
+            ';' => {
+                i = chars.len();
+                TokenKind::Comment(text(start + 1, i))
+            }
+            '\'' => {
+                // \' and \\ escape a quote and a backslash
+                let mut s = String::new();
+                i += 1;
+                while i < chars.len() && chars[i] != '\'' {
+                    if chars[i] == '\\' && matches!(chars.get(i + 1), Some('\'' | '\\')) {
+                        i += 1;
+                    }
+                    s.push(chars[i]);
+                    i += 1;
+                }
+                if i == chars.len() {
+                    return Err(AsmError::UnterminatedString {
+                        pos: SourcePos::new(span(start, i), line),
+                    });
+                }
+                i += 1;
+                TokenKind::Str(s)
+            }
+            _ if c.is_ascii_digit()
+                || ((c == '-' || c == '+') && chars.get(i + 1).is_some_and(|d| d.is_ascii_digit())) =>
+            {
+                i += 1;
+                while i < chars.len() && is_ident(chars[i]) {
+                    i += 1;
+                }
+                let token = text(start, i);
+                match token.parse::<i64>() {
+                    Ok(val) => TokenKind::Int(val),
+                    Err(_) => {
+                        return Err(AsmError::InvalidToken {
+                            pos: SourcePos::new(span(start, i), line),
+                            token,
+                        })
+                    }
+                }
+            }
+            _ if c.is_alphabetic() || c == '_' => {
+                while i < chars.len() && is_ident(chars[i]) {
+                    i += 1;
+                }
+                TokenKind::Ident(text(start, i))
+            }
+            _ => {
+                return Err(AsmError::InvalidToken {
+                    pos: SourcePos::new(span(start, start + 1), line),
+                    token: c.to_string(),
+                })
+            }
+        };
+        tokens.push(Token { kind, span: span(start, i) });
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(line: &str) -> Vec<TokenKind> {
+        tokenize(1, line).unwrap().into_iter().map(|t| t.kind).collect()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds("  mov  a, -5 ; set a"),
+            vec![Ident("mov".into()), Ident("a".into()), Comma, Int(-5), Comment(" set a".into())]
+        );
+        assert_eq!(kinds("msg 'x; y', b"), vec![Ident("msg".into()), Str("x; y".into()), Comma, Ident("b".into())]);
+        assert_eq!(kinds("proc_1:"), vec![Ident("proc_1".into()), Colon]);
+        assert_eq!(kinds(r"'it\'s \\ ok'"), vec![Str(r"it's \ ok".into())]);
+
+        let tokens = tokenize(3, "cmp 5, a").unwrap();
+        assert_eq!(tokens[1].span, Span { line: 3, column: 5, len: 1 });
+    }
+
+    #[test]
+    fn test_tokenize_errors() {
+        assert!(matches!(tokenize(1, "msg 'abc"), Err(AsmError::UnterminatedString { .. })));
+        assert!(matches!(tokenize(1, r"msg 'abc\'"), Err(AsmError::UnterminatedString { .. })));
+        assert!(matches!(tokenize(1, "mov a, 5x"), Err(AsmError::InvalidToken { token, .. }) if token == "5x"));
+        assert!(matches!(tokenize(1, "mov a, #5"), Err(AsmError::InvalidToken { token, .. }) if token == "#"));
+    }
+}
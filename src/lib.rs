@@ -0,0 +1,1915 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use decode::{Instr, Operand};
+use snapshot::History;
+
+mod analysis;
+mod batch;
+mod dap;
+mod debugger;
+mod decode;
+mod error;
+mod framing;
+mod lexer;
+mod link;
+mod lsp;
+mod output;
+mod parser;
+mod program;
+mod report;
+mod snapshot;
+mod state;
+mod trace;
+
+pub use batch::{run_batch, Job, JobResult};
+pub use dap::{serve_dap, DapServer};
+pub use debugger::Debugger;
+pub use error::{AsmError, Backtrace, Diagnostic, FaultKind, Frame, RunError, RuntimeError, Severity, SourcePos};
+pub use lexer::Span;
+pub use lsp::{serve_lsp, LspServer};
+pub use output::{OutputSink, SharedSink, StdoutSink};
+pub use program::Program;
+pub use report::{ExecutionReport, Termination};
+pub use snapshot::Snapshot;
+pub use state::CpuState;
+pub use trace::{JsonLinesTracer, SharedTracer, TraceEvent, Tracer};
+
+/// What happens when `inc`, `dec`, `add`, `sub`, `mul`, `div` or the
+/// target of a `jnz` overflows an i64.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ArithmeticMode {
+    Checked,     // stop with a runtime error
+    #[default]
+    Wrapping,    // two's complement wrap around, like a release build
+    Saturating,  // clamp to i64::MIN / i64::MAX
+}
+
+impl ArithmeticMode {
+    fn apply(self, a:i64, b:i64, checked:fn(i64,i64)->Option<i64>, wrapping:fn(i64,i64)->i64, saturating:fn(i64,i64)->i64) -> Option<i64> {
+        match self {
+            ArithmeticMode::Checked => checked(a, b),
+            ArithmeticMode::Wrapping => Some(wrapping(a, b)),
+            ArithmeticMode::Saturating => Some(saturating(a, b)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Register {
+    pub val:i64,
+}
+
+impl Register {
+    fn set_value(&mut self, new_val:i64 ) -> i64 {
+        self.val=new_val;
+        self.val
+    }
+
+    fn get_value(&self ) -> i64 {
+        self.val
+    }
+
+    fn inc(&mut self, mode:ArithmeticMode) -> Result<(), FaultKind> {
+        self.add(1, mode)
+    }
+
+    fn dec(&mut self, mode:ArithmeticMode) -> Result<(), FaultKind> {
+        self.sub(1, mode)
+    }
+
+    fn add(&mut self, val:i64, mode:ArithmeticMode) -> Result<(), FaultKind> {
+        self.val=mode.apply(self.val, val, i64::checked_add, i64::wrapping_add, i64::saturating_add).ok_or(FaultKind::Overflow)?;
+        Ok(())
+    }
+    fn sub(&mut self, val:i64, mode:ArithmeticMode) -> Result<(), FaultKind> {
+        self.val=mode.apply(self.val, val, i64::checked_sub, i64::wrapping_sub, i64::saturating_sub).ok_or(FaultKind::Overflow)?;
+        Ok(())
+    }
+    fn mul(&mut self, val:i64, mode:ArithmeticMode) -> Result<(), FaultKind> {
+        self.val=mode.apply(self.val, val, i64::checked_mul, i64::wrapping_mul, i64::saturating_mul).ok_or(FaultKind::Overflow)?;
+        Ok(())
+    }
+    // i64::MIN / -1 follows the arithmetic mode, a division by zero is always a fault
+    fn div(&mut self, val:i64, mode:ArithmeticMode) -> Result<(), FaultKind> {
+        if val==0 {
+            return Err(FaultKind::DivisionByZero);
+        }
+        self.val=mode.apply(self.val, val, i64::checked_div, i64::wrapping_div, i64::saturating_div).ok_or(FaultKind::DivisionOverflow)?;
+        Ok(())
+    }
+}
+
+#[derive(PartialEq,Debug,Clone)]
+pub enum Param {
+    Val(i64),
+    Register(String),
+  } 
+
+/// An instruction of the program. The semantics of each one are given by
+/// `Command::doc`.
+#[derive(PartialEq,Debug, Clone)]
+  pub enum Command {
+    Move(String, Param),  // mov x, y
+    Inc(String),          // inc x
+    Dec(String),          // dec x
+    Jnz(Param,Param),     // jnz x, y
+    Add(String,Param),    // add x, y
+    Sub(String, Param),   // sub x, y
+    Mul(String, Param),   // mul x, y
+    Div(String, Param),   // div x, y
+    Label(String),      // label: - define a label position (label = identifier + ":", an identifier being a string that does not match any other command). Jump commands and call are aimed to these labels positions in the program.
+    Jmp(Target),        // jmp lbl
+    Cmp(Param,Param),   // cmp x, y
+    Jne(Target),        // jne lbl
+    Je(Target),         // je lbl
+    Jge(Target),        // jge lbl
+    Jg(Target),         // jg lbl
+    Jle(Target),        // jle lbl
+    Jl(Target),         // jl lbl
+    Call(Target),       // call lbl
+    Ret,                // ret
+    Msg(Vec<MsgArg>),   // msg 'Register: ', x
+    End,                // end
+    Comment,            // ; comment - comments should not be taken in consideration during the execution of the program.
+}
+
+/// Argument of a msg instruction.
+#[derive(PartialEq,Debug,Clone)]
+pub enum MsgArg {
+    Text(String),     // 'text', quotes and backslashes escaped with \
+    Register(String),
+    Val(i64),
+}
+
+/// Destination of a jump or a call. The address is filled in by the link
+/// pass once every label of the program is known.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Target {
+    pub label: String,
+    pub address: Option<usize>,
+}
+
+impl Target {
+    pub fn new(label: &str) -> Self {
+        Target { label: label.to_string(), address: None }
+    }
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Param::Val(v) => write!(f, "{}", v),
+            Param::Register(r) => write!(f, "{}", r),
+        }
+    }
+}
+
+impl fmt::Display for MsgArg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MsgArg::Text(text) => write!(f, "'{}'", text.replace('\\', "\\\\").replace('\'', "\\'")),
+            MsgArg::Register(r) => write!(f, "{}", r),
+            MsgArg::Val(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// Writes the command back in assembly syntax.
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Move(r, p) => write!(f, "mov {}, {}", r, p),
+            Command::Inc(r) => write!(f, "inc {}", r),
+            Command::Dec(r) => write!(f, "dec {}", r),
+            Command::Jnz(a, b) => write!(f, "jnz {}, {}", a, b),
+            Command::Add(r, p) => write!(f, "add {}, {}", r, p),
+            Command::Sub(r, p) => write!(f, "sub {}, {}", r, p),
+            Command::Mul(r, p) => write!(f, "mul {}, {}", r, p),
+            Command::Div(r, p) => write!(f, "div {}, {}", r, p),
+            Command::Label(l) => write!(f, "{}:", l),
+            Command::Cmp(a, b) => write!(f, "cmp {}, {}", a, b),
+            Command::Ret => write!(f, "ret"),
+            Command::Msg(args) => {
+                write!(f, "msg")?;
+                for (i, arg) in args.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { " " } else { ", " }, arg)?;
+                }
+                Ok(())
+            },
+            Command::End => write!(f, "end"),
+            Command::Comment => write!(f, ";"),
+            Command::Jmp(t) | Command::Jne(t) | Command::Je(t) | Command::Jge(t)
+            | Command::Jg(t) | Command::Jle(t) | Command::Jl(t) | Command::Call(t) => {
+                write!(f, "{} {}", self.mnemonic(), t.label)
+            },
+        }
+    }
+}
+
+impl Command {
+    /// The mnemonic of every instruction.
+    pub const MNEMONICS: [&'static str; 20] = [
+        "mov", "inc", "dec", "jnz", "add", "sub", "mul", "div", "jmp", "cmp",
+        "jne", "je", "jge", "jg", "jle", "jl", "call", "ret", "msg", "end",
+    ];
+
+    /// Syntax and semantics of the instruction `mnemonic`, as shown by the
+    /// language server.
+    pub fn doc(mnemonic: &str) -> Option<&'static str> {
+        let doc = match mnemonic {
+            "mov" => "mov x, y - copies y (either an integer or the value of a register) into register x.",
+            "inc" => "inc x - increases the content of the register x by one.",
+            "dec" => "dec x - decreases the content of the register x by one.",
+            "jnz" => "jnz x, y - jumps to an instruction y steps away (positive means forward, negative means backward, y can be a register or a constant), but only if x (a constant or a register) is not zero.",
+            "add" => "add x, y - add the content of the register x with y (either an integer or the value of a register) and stores the result in x (i.e. register[x] += y).",
+            "sub" => "sub x, y - subtract y (either an integer or the value of a register) from the register x and stores the result in x (i.e. register[x] -= y).",
+            "mul" => "mul x, y - same with multiply (i.e. register[x] *= y).",
+            "div" => "div x, y - same with integer division (i.e. register[x] /= y).",
+            "jmp" => "jmp lbl - jumps to the label lbl.",
+            "cmp" => "cmp x, y - compares x (either an integer or the value of a register) and y (either an integer or the value of a register). The result is used in the conditional jumps (jne, je, jge, jg, jle and jl)",
+            "jne" => "jne lbl - jump to the label lbl if the values of the previous cmp command were not equal.",
+            "je" => "je lbl - jump to the label lbl if the values of the previous cmp command were equal.",
+            "jge" => "jge lbl - jump to the label lbl if x was greater or equal than y in the previous cmp command.",
+            "jg" => "jg lbl - jump to the label lbl if x was greater than y in the previous cmp command.",
+            "jle" => "jle lbl - jump to the label lbl if x was less or equal than y in the previous cmp command.",
+            "jl" => "jl lbl - jump to the label lbl if x was less than y in the previous cmp command.",
+            "call" => "call lbl - call to the subroutine identified by lbl. When a ret is found in a subroutine, the instruction pointer should return to the instruction next to this call command.",
+            "ret" => "ret - when a ret is found in a subroutine, the instruction pointer should return to the instruction that called the current function.",
+            "msg" => "msg 'Register: ', x - this instruction stores the output of the program. It may contain text strings (delimited by single quotes) and registers. The number of arguments isn't limited and will vary, depending on the program.",
+            "end" => "end - this instruction indicates that the program ends correctly, so the stored output is returned (if the program terminates without this instruction it should return the default output).",
+            _ => return None,
+        };
+        Some(doc)
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Command::Move(..) => "mov",
+            Command::Inc(_) => "inc",
+            Command::Dec(_) => "dec",
+            Command::Jnz(..) => "jnz",
+            Command::Add(..) => "add",
+            Command::Sub(..) => "sub",
+            Command::Mul(..) => "mul",
+            Command::Div(..) => "div",
+            Command::Label(_) => "label",
+            Command::Jmp(_) => "jmp",
+            Command::Cmp(..) => "cmp",
+            Command::Jne(_) => "jne",
+            Command::Je(_) => "je",
+            Command::Jge(_) => "jge",
+            Command::Jg(_) => "jg",
+            Command::Jle(_) => "jle",
+            Command::Jl(_) => "jl",
+            Command::Call(_) => "call",
+            Command::Ret => "ret",
+            Command::Msg(_) => "msg",
+            Command::End => "end",
+            Command::Comment => ";",
+        }
+    }
+
+    /// Target of a jump or a call.
+    pub fn target(&self) -> Option<&Target> {
+        match self {
+            Command::Jmp(x) | Command::Jne(x) | Command::Je(x) | Command::Jge(x)
+            | Command::Jg(x) | Command::Jle(x) | Command::Jl(x) | Command::Call(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn target_mut(&mut self) -> Option<&mut Target> {
+        match self {
+            Command::Jmp(x) | Command::Jne(x) | Command::Je(x) | Command::Jge(x)
+            | Command::Jg(x) | Command::Jle(x) | Command::Jl(x) | Command::Call(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Comparison {
+    Equal,
+    Less,
+    Greater
+}
+
+impl Comparison {
+    /// `equal`, `less` or `greater`, as written in traces and saved states.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Comparison::Equal => "equal",
+            Comparison::Less => "less",
+            Comparison::Greater => "greater",
+        }
+    }
+
+    /// The comparison called `name`, see `name`.
+    pub fn from_name(name: &str) -> Option<Comparison> {
+        match name {
+            "equal" => Some(Comparison::Equal),
+            "less" => Some(Comparison::Less),
+            "greater" => Some(Comparison::Greater),
+            _ => None,
+        }
+    }
+}
+
+/// What happened on a call to `Cpu::step`, `Cpu::resume` or `Cpu::run_until`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StepOutcome {
+    Stepped,            // an instruction ran, the program goes on
+    Breakpoint(usize),  // stopped before the instruction at this address
+    End,                // `end` was executed
+    NoEnd,              // the program ran past its last instruction
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Breakpoint {
+    Address(usize),
+    Label(String),
+    Line(usize),    // first instruction on or after this source line
+}
+
+/// Stops a running `Cpu` from another thread: clone it, hand it to the
+/// cpu with `Cpu::set_cancel_token` and call `cancel` on the other copy.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// What `ret` does when no call is pending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EmptyReturn {
+    #[default]
+    Fault,  // stop with a `FaultKind::ReturnWithoutCall` fault
+    End,    // terminate the program as if it were `end`
+}
+
+/// What reading a register that was never written does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UninitializedReads {
+    #[default]
+    Zero,   // the register reads 0, as on Codewars
+    Fault,  // stop with a `FaultKind::UninitializedRegister` fault
+    Warn,   // reads 0, `load_code` warns about the reads that may happen
+}
+
+/// Default limit of `Cpu::set_max_call_depth`.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 100_000;
+
+/// Checkpoint interval used by the debuggers, see `Cpu::set_checkpoint_interval`.
+pub const DEFAULT_CHECKPOINT_INTERVAL: u64 = 1000;
+
+/// Number of instructions between two checks of the cancel token and the
+/// deadline.
+const INTERRUPT_CHECK_INTERVAL: u64 = 1024;
+
+#[derive(Clone)]
+pub struct Cpu{
+    regs:Vec<Register>,
+    program:Arc<Program>,
+    compare:Option<Comparison>,
+    sub_calls:Vec<usize>,
+    declared_regs:Option<Vec<String>>,
+    ip:usize,
+    out:String,
+    breakpoints:BTreeSet<usize>,
+    steps:u64,
+    max_steps:Option<u64>,
+    cancel:Option<CancelToken>,
+    deadline:Option<Instant>,
+    arithmetic:ArithmeticMode,
+    fault_handler:Option<String>,
+    empty_return:EmptyReturn,
+    max_call_depth:Option<usize>,
+    uninitialized:UninitializedReads,
+    written:Vec<bool>,
+    max_depth:usize,
+    sink:Option<SharedSink>,
+    tracer:Option<SharedTracer>,
+    msg_buf:String,
+    history:Option<History>,
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cpu {
+    pub fn new() -> Self {
+
+        Cpu { regs: Vec::new(),
+             program: Arc::default(),
+             compare:None,
+            sub_calls: Vec::new(),
+            declared_regs: None,
+            ip: 0,
+            out: String::new(),
+            breakpoints: BTreeSet::new(),
+            steps: 0,
+            max_steps: None,
+            cancel: None,
+            deadline: None,
+            arithmetic: ArithmeticMode::default(),
+            fault_handler: None,
+            empty_return: EmptyReturn::default(),
+            max_call_depth: Some(DEFAULT_MAX_CALL_DEPTH),
+            uninitialized: UninitializedReads::default(),
+            written: Vec::new(),
+            max_depth: 0,
+            sink: None,
+            tracer: None,
+            msg_buf: String::new(),
+            history: None }
+    }
+
+    /// Strict mode: restricts the register names accepted by `load_code`
+    /// to `names`. `None` (the default) accepts any identifier.
+    pub fn set_declared_registers(&mut self, names: Option<&[&str]>) {
+        self.declared_regs = names.map(|names| names.iter().map(|n| n.to_string()).collect());
+    }
+
+    pub fn load_code_from_vec(&mut self, code:&[&str]) -> Result<Vec<Diagnostic>, Vec<Diagnostic>>{
+
+        self.load_code(code.join("\n").as_str())
+
+    }
+
+    /// A cpu running an already loaded program, see `Program::cpu`.
+    pub fn from_program(program: Arc<Program>) -> Self {
+        let mut cpu=Cpu::new();
+        cpu.set_program(program);
+        cpu
+    }
+
+    /// Loads a program, skipping the lines that fail to parse so the whole
+    /// text is checked in one pass, then links the labels used by jumps and
+    /// calls. Returns the warnings on success, or every diagnostic (errors
+    /// and warnings) when at least one error was found. In both cases `code`
+    /// holds whatever could be parsed. The loaded `Program` can then be
+    /// shared with other cpus, see `program`.
+    pub fn load_code(&mut self, txt: &str) -> Result<Vec<Diagnostic>, Vec<Diagnostic>> {
+        let warn=self.uninitialized==UninitializedReads::Warn;
+        let (program, diagnostics)=Program::build(txt, self.declared_regs.as_deref(), warn);
+        self.set_program(Arc::new(program));
+        if diagnostics.iter().any(Diagnostic::is_error) {
+            Err(diagnostics)
+        } else {
+            Ok(diagnostics)
+        }
+    }
+
+    /// Switches to another program and resets the cpu. The settings and
+    /// breakpoints are kept.
+    pub fn set_program(&mut self, program: Arc<Program>){
+        self.program=program;
+        self.reset();
+    }
+
+    pub fn program(&self) -> &Arc<Program>{
+        &self.program
+    }
+
+    /// Puts the cpu back in its initial state: registers at 0, no
+    /// comparison, no pending call, no output, `ip` at the start.
+    pub fn reset(&mut self){
+        let n=self.program.decoded.reg_names.len();
+        self.regs=vec![Register{val:0}; n];
+        self.written=vec![false; n];
+        self.compare=None;
+        self.sub_calls.clear();
+        self.ip=0;
+        self.steps=0;
+        self.max_depth=0;
+        self.out.clear();
+        if let Some(history)=&mut self.history {
+            history.clear();
+        }
+    }
+
+    /// Runs the program from its first instruction, ignoring breakpoints.
+    /// Returns the msg output if `end` is reached. The registers keep their
+    /// values, they are the input of the program; `reset` clears them.
+    pub fn run (&mut self) -> Result<String,RunError>{
+        self.ip=0;
+        self.steps=0;
+        self.max_depth=0;
+        self.compare=None;
+        self.sub_calls.clear();
+        self.out.clear();
+        if let Some(history)=&mut self.history {
+            history.clear();
+        }
+        match self.run_to(None, false)? {
+            StepOutcome::End => Ok(self.out.clone()),
+            _ => Err(RunError::NoEnd),
+        }
+    }
+
+    /// Runs the program like `run` and reports how it went.
+    pub fn execute(&mut self) -> ExecutionReport{
+        let termination=match self.run() {
+            Ok(_) => Termination::End,
+            Err(e) => Termination::Failed(e),
+        };
+        ExecutionReport {
+            output: self.out.clone(),
+            termination,
+            registers: self.registers().map(|(name, val)| (name.to_string(), val)).collect(),
+            steps: self.steps,
+            max_call_depth: self.max_depth,
+        }
+    }
+
+    /// Carries on from the current instruction until `end`, the end of the
+    /// code or a breakpoint. The instruction under `ip` always runs, so
+    /// resuming from a breakpoint doesn't stop on it again.
+    pub fn resume(&mut self) -> Result<StepOutcome,RunError>{
+        self.run_to(None, true)
+    }
+
+    /// Like `resume`, also stopping at `breakpoint`.
+    pub fn run_until(&mut self, breakpoint: &Breakpoint) -> Result<StepOutcome,RunError>{
+        let address=self.resolve_breakpoint(breakpoint).map_err(RunError::InvalidBreakpoint)?;
+        self.run_to(Some(address), true)
+    }
+
+    /// Executes the instruction under `ip`; a `call` runs until its
+    /// subroutine returns, unless a breakpoint is hit on the way.
+    pub fn step_over(&mut self) -> Result<StepOutcome,RunError>{
+        let depth=self.sub_calls.len();
+        match self.step()? {
+            StepOutcome::Stepped => self.run_deeper_than(depth),
+            outcome => Ok(outcome),
+        }
+    }
+
+    /// Runs until the current subroutine returns or a breakpoint is hit.
+    /// Outside any subroutine, this is `resume`.
+    pub fn step_out(&mut self) -> Result<StepOutcome,RunError>{
+        match self.sub_calls.len() {
+            0 => self.resume(),
+            depth => self.run_deeper_than(depth-1),
+        }
+    }
+
+    fn run_deeper_than(&mut self, depth: usize) -> Result<StepOutcome,RunError>{
+        while self.sub_calls.len()>depth {
+            match self.step()? {
+                StepOutcome::Stepped if self.breakpoints.contains(&self.ip) => return Ok(StepOutcome::Breakpoint(self.ip)),
+                StepOutcome::Stepped => {},
+                outcome => return Ok(outcome),
+            }
+        }
+        Ok(StepOutcome::Stepped)
+    }
+
+    /// Runs until `end`, the end of the code, `stop` or, if `breakpoints`
+    /// is set, a breakpoint.
+    fn run_to(&mut self, stop: Option<usize>, breakpoints: bool) -> Result<StepOutcome,RunError>{
+        let breakpoints=breakpoints && !self.breakpoints.is_empty();
+        if stop.is_none() && !breakpoints && self.tracer.is_none() && self.history.is_none() {
+            return match self.uninitialized {
+                UninitializedReads::Fault => self.run_fast::<true>(),
+                _ => self.run_fast::<false>(),
+            };
+        }
+        loop {
+            match self.step()? {
+                StepOutcome::Stepped if Some(self.ip)==stop || (breakpoints && self.breakpoints.contains(&self.ip)) => {
+                    return Ok(StepOutcome::Breakpoint(self.ip));
+                },
+                StepOutcome::Stepped => {},
+                outcome => return Ok(outcome),
+            }
+        }
+    }
+
+    /// `run_to` with nothing to stop at or to observe: the instructions
+    /// run in a tight loop on a local `ip`, written back when it stops.
+    /// `CHECK_INIT` is whether reads of unwritten registers fault.
+    fn run_fast<const CHECK_INIT: bool>(&mut self) -> Result<StepOutcome,RunError>{
+        let program=Arc::clone(&self.program);
+        let instrs=&program.decoded.instrs[..];
+        let mut ip=self.ip;
+        let result=loop {
+            // the budget and the interrupts are only checked between blocks
+            let mut block=INTERRUPT_CHECK_INTERVAL;
+            if let Some(max)=self.max_steps {
+                if self.steps>=max {
+                    break Err(RunError::BudgetExhausted { ip, steps: self.steps });
+                }
+                block=block.min(max-self.steps);
+            }
+            self.ip=ip;
+            if let Err(e)=self.check_interrupt() {
+                break Err(e);
+            }
+            let mut done=0;
+            let stop=loop {
+                if done==block {
+                    break None;
+                }
+                let Some(&instr)=instrs.get(ip) else { break Some(Ok(StepOutcome::NoEnd)) };
+                done+=1;
+                let checked=if CHECK_INIT { self.check_initialized(instr) } else { Ok(()) };
+                let result=checked.and_then(|_| self.exec_instr(instr, &mut ip));
+                match result {
+                    Ok(StepOutcome::Stepped) => {},
+                    Ok(outcome) => break Some(Ok(outcome)),
+                    Err(kind) => {
+                        self.ip=ip;
+                        match self.fault(kind) {
+                            Ok(_) => ip=self.ip,
+                            Err(e) => break Some(Err(e)),
+                        }
+                    },
+                }
+            };
+            self.steps+=done;
+            if let Some(result)=stop {
+                break result;
+            }
+        };
+        self.ip=ip;
+        result
+    }
+
+    /// Goes back one instruction, see `set_checkpoint_interval`. At the
+    /// start of the recorded history, nothing happens.
+    pub fn reverse_step(&mut self) -> Result<StepOutcome,RunError>{
+        self.rewind_to(self.steps.saturating_sub(1))?;
+        Ok(StepOutcome::Stepped)
+    }
+
+    /// Goes back to the last time a breakpoint was about to execute, or to
+    /// the start of the recorded history if there is none. The output sink
+    /// and the tracer don't see the instructions re-executed to find it.
+    pub fn reverse_continue(&mut self) -> Result<StepOutcome,RunError>{
+        self.unobserved(Cpu::search_back)
+    }
+
+    fn search_back(&mut self) -> Result<StepOutcome,RunError>{
+        let now=self.steps;
+        let starts=self.history.as_ref().ok_or(RunError::NoHistory)?.starts_before(now);
+        let mut end=now;
+        for start in starts {
+            self.rewind_to(start)?;
+            let mut hit=None;
+            loop {
+                if self.breakpoints.contains(&self.ip) {
+                    hit=Some(self.steps);
+                }
+                if self.steps+1>=end {
+                    break; // the last one may have faulted, it isn't re-executed
+                }
+                self.step()?;
+            }
+            if let Some(hit)=hit {
+                self.rewind_to(hit)?;
+                return Ok(StepOutcome::Breakpoint(self.ip));
+            }
+            end=start;
+        }
+        self.rewind_to(0)?;
+        Ok(StepOutcome::Stepped)
+    }
+
+    /// Restores the state after `steps` instructions, from the checkpoint
+    /// before it and re-executing the rest. The output sink and the tracer
+    /// don't see the re-executed instructions.
+    fn rewind_to(&mut self, steps: u64) -> Result<(),RunError>{
+        self.unobserved(|cpu| {
+            let history=cpu.history.as_mut().ok_or(RunError::NoHistory)?;
+            let Some(snapshot)=history.rewind(steps) else { return Ok(()) };
+            cpu.load_snapshot(&snapshot);
+            while cpu.steps<steps {
+                cpu.step()?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Runs `f` with the output sink and the tracer detached, for
+    /// instructions executed again.
+    fn unobserved<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T{
+        let (sink, tracer)=(self.sink.take(), self.tracer.take());
+        let result=f(self);
+        if sink.is_some() {
+            self.out.clear();
+        }
+        self.sink=sink;
+        self.tracer=tracer;
+        result
+    }
+
+    /// The execution state, to `restore` later.
+    pub fn snapshot(&self) -> Snapshot{
+        Snapshot {
+            regs: self.regs.iter().map(Register::get_value).collect(),
+            written: self.written.clone(),
+            compare: self.compare.clone(),
+            sub_calls: self.sub_calls.clone(),
+            ip: self.ip,
+            steps: self.steps,
+            max_depth: self.max_depth,
+            output_len: self.out.len(),
+        }
+    }
+
+    /// Goes back to a state taken by `snapshot` earlier in the same run of
+    /// the same program.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), String>{
+        if snapshot.regs.len()!=self.regs.len() || snapshot.ip>self.program.code.len() {
+            return Err("Snapshot of another program".to_string());
+        }
+        if snapshot.output_len>self.out.len() {
+            return Err("Snapshot of a later state".to_string());
+        }
+        self.load_snapshot(snapshot);
+        self.checkpoint(true);
+        Ok(())
+    }
+
+    fn load_snapshot(&mut self, snapshot: &Snapshot){
+        self.regs=snapshot.regs.iter().map(|&val| Register{val}).collect();
+        self.written.clone_from(&snapshot.written);
+        self.compare.clone_from(&snapshot.compare);
+        self.sub_calls.clone_from(&snapshot.sub_calls);
+        self.ip=snapshot.ip;
+        self.steps=snapshot.steps;
+        self.max_depth=snapshot.max_depth;
+        self.out.truncate(snapshot.output_len);
+    }
+
+    /// The complete execution state, e.g. to resume the program elsewhere
+    /// with `load_state`, or as a crash dump after a fault.
+    pub fn save_state(&self) -> CpuState{
+        let names=&self.program.decoded.reg_names;
+        CpuState {
+            program_hash: self.program.hash(),
+            registers: self.registers().map(|(name, val)| (name.to_string(), val)).collect(),
+            // the writes are only tracked when unwritten reads fault
+            written: (self.uninitialized==UninitializedReads::Fault)
+                .then(|| names.iter().zip(&self.written).filter(|(_, &w)| w).map(|(name, _)| name.clone()).collect()),
+            comparison: self.compare.clone(),
+            call_stack: self.sub_calls.clone(),
+            ip: self.ip,
+            steps: self.steps,
+            max_call_depth: self.max_depth,
+            output: self.out.clone(),
+        }
+    }
+
+    /// Restores a state saved by `save_state` with the same program, to
+    /// carry on with `resume` or to inspect it. A cpu whose unwritten reads
+    /// fault needs a state saved with that setting too.
+    pub fn load_state(&mut self, state: &CpuState) -> Result<(), String>{
+        if state.program_hash!=self.program.hash() {
+            return Err("State saved with another program".to_string());
+        }
+        if state.written.is_none() && self.uninitialized==UninitializedReads::Fault {
+            return Err("State saved without the written registers, save it with uninitialized reads set to fault".to_string());
+        }
+        let decoded=&self.program.decoded;
+        let mut regs=vec![0; decoded.reg_names.len()];
+        let mut written=vec![false; decoded.reg_names.len()];
+        for &(ref name, val) in &state.registers {
+            let i=decoded.reg_index(name).ok_or_else(|| format!("Register {} is not used by the program", name))?;
+            regs[i]=val;
+        }
+        for name in state.written.iter().flatten() {
+            let i=decoded.reg_index(name).ok_or_else(|| format!("Register {} is not used by the program", name))?;
+            written[i]=true;
+        }
+        let len=self.program.code.len();
+        if state.ip>len || state.call_stack.iter().any(|&address| address>=len) {
+            return Err("Address outside the code".to_string());
+        }
+        let snapshot=Snapshot {
+            regs,
+            written,
+            compare: state.comparison.clone(),
+            sub_calls: state.call_stack.clone(),
+            ip: state.ip,
+            steps: state.steps,
+            max_depth: state.max_call_depth,
+            output_len: state.output.len(),
+        };
+        self.out.clone_from(&state.output);
+        self.load_snapshot(&snapshot);
+        if let Some(history)=&mut self.history {
+            history.clear();
+        }
+        self.checkpoint(true);
+        Ok(())
+    }
+
+    /// Records the current state in the history, if there is one.
+    fn checkpoint(&mut self, pinned: bool){
+        let snapshot=self.snapshot();
+        if let Some(history)=&mut self.history {
+            history.record(snapshot, pinned);
+        }
+    }
+
+    /// Executes the instruction under `ip`, unless the instruction budget
+    /// is spent.
+    #[inline]
+    pub fn step(&mut self) -> Result<StepOutcome,RunError>{
+        let Some(&instr)=self.program.decoded.instrs.get(self.ip) else {
+            return Ok(StepOutcome::NoEnd);
+        };
+        if self.max_steps.is_some_and(|max| self.steps>=max) {
+            return Err(RunError::BudgetExhausted { ip: self.ip, steps: self.steps });
+        }
+        if self.steps.is_multiple_of(INTERRUPT_CHECK_INTERVAL) {
+            self.check_interrupt()?;
+        }
+        if self.tracer.is_some() || self.history.is_some() {
+            return self.step_observed(instr);
+        }
+        self.steps+=1;
+        self.exec_at_ip(instr)
+    }
+
+    fn exec_at_ip(&mut self, instr: Instr) -> Result<StepOutcome,RunError>{
+        let mut ip=self.ip;
+        let result=self.exec(instr, &mut ip);
+        self.ip=ip;
+        match result {
+            Ok(outcome) => Ok(outcome),
+            Err(kind) => self.fault(kind),
+        }
+    }
+
+    /// `step` recording the history and reporting to the tracer.
+    #[cold]
+    fn step_observed(&mut self, instr: Instr) -> Result<StepOutcome,RunError>{
+        if self.history.as_ref().is_some_and(|history| history.due(self.steps)) {
+            self.checkpoint(false);
+        }
+        self.steps+=1;
+        if self.tracer.is_some() {
+            return self.step_traced(instr);
+        }
+        self.exec_at_ip(instr)
+    }
+
+    /// `step` reporting the executed instruction to the tracer.
+    fn step_traced(&mut self, instr: Instr) -> Result<StepOutcome,RunError>{
+        let address=self.ip;
+        let before: Vec<i64>=self.regs.iter().map(Register::get_value).collect();
+        let result=self.exec_at_ip(instr);
+        let event=TraceEvent {
+            step: self.steps,
+            address,
+            line: self.program.positions.get(address).map(|pos| pos.line),
+            command: &self.program.code[address],
+            changes: self.registers().zip(&before).filter(|((_, val), old)| val!=*old).map(|(reg, _)| reg).collect(),
+            comparison: self.compare.as_ref(),
+            depth: self.sub_calls.len(),
+        };
+        if let Some(tracer)=&self.tracer {
+            tracer.lock().unwrap_or_else(|e| e.into_inner()).record(&event);
+        }
+        result
+    }
+
+    /// Executes `instr`, the instruction at `ip`, moving `ip` to the next
+    /// one. Nothing changes when it faults.
+    #[inline(always)]
+    fn exec(&mut self, instr: Instr, ip: &mut usize) -> Result<StepOutcome,FaultKind>{
+        if self.uninitialized==UninitializedReads::Fault {
+            self.check_initialized(instr)?;
+        }
+        self.exec_instr(instr, ip)
+    }
+
+    /// `exec` without the check of the registers read.
+    #[inline(always)]
+    fn exec_instr(&mut self, instr: Instr, ip: &mut usize) -> Result<StepOutcome,FaultKind>{
+        match instr {
+
+            Instr::Dec(r) => {
+                self.regs[r].dec(self.arithmetic)?;
+            },
+            Instr::Inc(r) => {
+                self.regs[r].inc(self.arithmetic)?;
+            },
+            Instr::Mov(r, p) => {
+                let val = self.get_param_value(p);
+                self.regs[r].set_value(val);
+            },
+            Instr::Jnz(a, b) => {
+                let condition=self.get_param_value(a);
+                let jump = self.get_param_value(b);
+                if condition !=0 {
+                    let len=self.program.decoded.instrs.len() as i64;
+                    let target=self.arithmetic.apply(*ip as i64, jump, i64::checked_add, i64::wrapping_add, i64::saturating_add);
+                    match target {
+                        Some(target) if (0..=len).contains(&target) => *ip=target as usize,
+                        Some(_) => return Err(FaultKind::BadJump { offset: jump }),
+                        None => return Err(FaultKind::Overflow),
+                    }
+                    return Ok(StepOutcome::Stepped);
+                }
+            },
+            Instr::Add(r, p) => {
+                let val = self.get_param_value(p);
+                self.regs[r].add(val, self.arithmetic)?;
+            },
+            Instr::Sub(r, p) => {
+                let val = self.get_param_value(p);
+                self.regs[r].sub(val, self.arithmetic)?;
+            },
+            Instr::Mul(r, p) => {
+                let val = self.get_param_value(p);
+                self.regs[r].mul(val, self.arithmetic)?;
+            },
+            Instr::Div(r, p) => {
+                let val = self.get_param_value(p);
+                self.regs[r].div(val, self.arithmetic)?;
+            },
+            Instr::Cmp(p1, p2) => {
+                let val_1 = self.get_param_value(p1);
+                let val_2 = self.get_param_value(p2);
+                if val_1==val_2 {self.compare=Some(Comparison::Equal);}
+                else if val_1<val_2 { self.compare=Some(Comparison::Less);}
+                else { self.compare=Some(Comparison::Greater);}
+            },
+            Instr::Nop => {},
+            Instr::Jmp(x) => {
+                *ip=x; return Ok(StepOutcome::Stepped);
+            },
+            Instr::Jne(x) => {
+                if self.compare.is_some() && self.compare!=Some(Comparison::Equal) {
+                    *ip=x; return Ok(StepOutcome::Stepped);
+                }
+            },
+            Instr::Je(x) => {
+                if self.compare==Some(Comparison::Equal) {
+                    *ip=x; return Ok(StepOutcome::Stepped);
+                }
+            },
+            Instr::Jge(x) => {
+                if self.compare==Some(Comparison::Equal) || self.compare==Some(Comparison::Greater) {
+                    *ip=x; return Ok(StepOutcome::Stepped);
+                }
+            },
+            Instr::Jg(x) => {
+                if self.compare==Some(Comparison::Greater) {
+                    *ip=x; return Ok(StepOutcome::Stepped);
+                }
+            },
+            Instr::Jle(x) => {
+                if self.compare==Some(Comparison::Equal) || self.compare==Some(Comparison::Less) {
+                    *ip=x; return Ok(StepOutcome::Stepped);
+                }
+            },
+            Instr::Jl(x) => {
+                if self.compare==Some(Comparison::Less) {
+                    *ip=x; return Ok(StepOutcome::Stepped);
+                }
+            },
+            Instr::Call(x) => {
+                if self.max_call_depth.is_some_and(|max| self.sub_calls.len()>=max) {
+                    return Err(FaultKind::CallDepthExceeded);
+                }
+                self.sub_calls.push(*ip);
+                self.max_depth=self.max_depth.max(self.sub_calls.len());
+                *ip=x;
+                return Ok(StepOutcome::Stepped);
+            },
+            Instr::Ret => {
+                match (self.sub_calls.pop(), self.empty_return) {
+                    (Some(x), _) => *ip=x,
+                    (None, EmptyReturn::Fault) => return Err(FaultKind::ReturnWithoutCall),
+                    (None, EmptyReturn::End) => return Ok(StepOutcome::End),
+                }
+            },
+            Instr::Msg(m) => match &self.sink {
+                None => self.program.decoded.write_msg(m, &self.regs, &mut self.out),
+                Some(sink) => {
+                    self.msg_buf.clear();
+                    self.program.decoded.write_msg(m, &self.regs, &mut self.msg_buf);
+                    sink.lock().unwrap_or_else(|e| e.into_inner()).write_msg(&self.msg_buf);
+                },
+            },
+            Instr::End => {return Ok(StepOutcome::End)},
+        }
+        *ip+=1;
+        Ok(StepOutcome::Stepped)
+    }
+
+    /// Fails if `instr` reads a register that was never written, then marks
+    /// the register it writes.
+    fn check_initialized(&mut self, instr: Instr) -> Result<(),FaultKind>{
+        let mut unset=None;
+        self.program.decoded.reads(instr, |r| if !self.written[r] { unset=unset.or(Some(r)) });
+        if let Some(r)=unset {
+            return Err(FaultKind::UninitializedRegister { name: self.program.decoded.reg_names[r].clone() });
+        }
+        if let Some(r)=instr.written() {
+            self.written[r]=true;
+        }
+        Ok(())
+    }
+
+    /// Calls the fault handler if there is one, otherwise stops the program
+    /// with a `RuntimeError` describing the faulting instruction. Calling the
+    /// handler is limited by the call depth like `call`, so a handler that
+    /// faults itself stops with `CallDepthExceeded` instead of recursing.
+    fn fault(&mut self, mut kind: FaultKind) -> Result<StepOutcome,RunError>{
+        if let Some(&handler)=self.fault_handler.as_ref().and_then(|label| self.program.labels.get(label)) {
+            if self.max_call_depth.is_some_and(|max| self.sub_calls.len()>=max) {
+                kind=FaultKind::CallDepthExceeded;
+            } else if kind!=FaultKind::CallDepthExceeded {
+                self.sub_calls.push(self.ip);
+                self.max_depth=self.max_depth.max(self.sub_calls.len());
+                self.ip=handler;
+                return Ok(StepOutcome::Stepped);
+            }
+        }
+        Err(RunError::Fault(Box::new(RuntimeError {
+            kind,
+            address: self.ip,
+            label: self.enclosing_label(self.ip).map(str::to_string),
+            pos: self.program.positions.get(self.ip).cloned(),
+            registers: self.registers().map(|(name, val)| (name.to_string(), val)).collect(),
+            backtrace: self.backtrace(),
+        })))
+    }
+
+    /// The pending calls as frames, innermost first: the address of each
+    /// `call` with the label of the subroutine it is in.
+    pub fn backtrace(&self) -> Vec<Frame>{
+        let mut frames: Vec<Frame>=Vec::with_capacity(self.sub_calls.len());
+        for &address in self.sub_calls.iter().rev() {
+            // a recursion repeats the same frame, computed once
+            let frame=match frames.last() {
+                Some(last) if last.address==address => last.clone(),
+                _ => self.frame(address),
+            };
+            frames.push(frame);
+        }
+        frames
+    }
+
+    /// `address` with the label of the subroutine it is in and its line.
+    pub fn frame(&self, address: usize) -> Frame{
+        Frame {
+            address,
+            label: self.enclosing_label(address).map(str::to_string),
+            line: self.program.positions.get(address).map(|pos| pos.line),
+        }
+    }
+
+    /// The last label at or before `address`.
+    fn enclosing_label(&self, address: usize) -> Option<&str>{
+        self.program.labels.iter()
+            .filter(|(_, &a)| a<=address)
+            .max_by(|x, y| x.1.cmp(y.1).then(y.0.cmp(x.0)))
+            .map(|(name, _)| name.as_str())
+    }
+
+    fn check_interrupt(&self) -> Result<(),RunError>{
+        if self.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+            return Err(RunError::Cancelled { ip: self.ip, steps: self.steps, output: self.out.clone() });
+        }
+        if self.deadline.is_some_and(|deadline| Instant::now()>=deadline) {
+            return Err(RunError::TimedOut { ip: self.ip, steps: self.steps, output: self.out.clone() });
+        }
+        Ok(())
+    }
+
+    /// Address of a breakpoint, checking it's inside the code.
+    fn resolve_breakpoint(&self, breakpoint: &Breakpoint) -> Result<usize,String>{
+        let address=match breakpoint {
+            Breakpoint::Address(address) => *address,
+            Breakpoint::Label(name) => *self.program.labels.get(name).ok_or_else(|| format!("Unknown label {}", name))?,
+            Breakpoint::Line(line) => self.program.positions.iter().position(|pos| pos.line>=*line)
+                .ok_or_else(|| format!("No code on or after line {}", line))?,
+        };
+        if address>=self.program.code.len() {
+            return Err(format!("Address {} is outside the code", address));
+        }
+        Ok(address)
+    }
+
+    /// Adds a breakpoint, returning its address.
+    pub fn set_breakpoint(&mut self, breakpoint: &Breakpoint) -> Result<usize,String>{
+        let address=self.resolve_breakpoint(breakpoint)?;
+        self.breakpoints.insert(address);
+        Ok(address)
+    }
+
+    pub fn remove_breakpoint(&mut self, address: usize) -> bool{
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item=usize> + '_{
+        self.breakpoints.iter().copied()
+    }
+
+    /// Limits the number of instructions executed by `run`, or by the
+    /// stepping functions since the last `run`. `None` (the default) means
+    /// no limit.
+    pub fn set_max_steps(&mut self, max_steps: Option<u64>){
+        self.max_steps=max_steps;
+    }
+
+    /// Token checked every few instructions, see `CancelToken`.
+    pub fn set_cancel_token(&mut self, token: Option<CancelToken>){
+        self.cancel=token;
+    }
+
+    /// Wall-clock limit, checked every few instructions.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>){
+        self.deadline=deadline;
+    }
+
+    pub fn set_arithmetic_mode(&mut self, mode: ArithmeticMode){
+        self.arithmetic=mode;
+    }
+
+    /// Label called when an instruction faults (division by zero, overflow
+    /// in checked mode, bad jump), instead of stopping the program. The
+    /// handler's `ret` resumes after the faulting instruction. Fails if the
+    /// label isn't in the loaded program, so set it after loading.
+    pub fn set_fault_handler(&mut self, label: Option<&str>) -> Result<(),String>{
+        if let Some(label)=label.filter(|label| !self.program.labels.contains_key(*label)) {
+            return Err(format!("unknown fault handler label `{}`", label));
+        }
+        self.fault_handler=label.map(str::to_string);
+        Ok(())
+    }
+
+    /// What `ret` does on an empty call stack, a fault by default.
+    pub fn set_empty_return(&mut self, empty_return: EmptyReturn){
+        self.empty_return=empty_return;
+    }
+
+    /// Maximum number of pending calls, `DEFAULT_MAX_CALL_DEPTH` by default.
+    /// A `call` beyond it faults with `FaultKind::CallDepthExceeded`, which
+    /// the fault handler doesn't catch. `None` removes the limit.
+    pub fn set_max_call_depth(&mut self, depth: Option<usize>){
+        self.max_call_depth=depth;
+    }
+
+    /// What reading a register that was never written does, implicit zero
+    /// by default. Set it before `load_code` for `UninitializedReads::Warn`.
+    pub fn set_uninitialized_reads(&mut self, policy: UninitializedReads){
+        self.uninitialized=policy;
+    }
+
+    /// Source position of the instruction at `address`.
+    pub fn source_pos(&self, address: usize) -> Option<&SourcePos>{
+        self.program.source_pos(address)
+    }
+
+    /// Instructions executed since the start of the last `run`.
+    pub fn steps(&self) -> u64{
+        self.steps
+    }
+
+    /// Deepest call stack reached since the start of the last `run`.
+    pub fn max_depth(&self) -> usize{
+        self.max_depth
+    }
+
+    /// Address of the next instruction to execute.
+    pub fn ip(&self) -> usize{
+        self.ip
+    }
+
+    pub fn set_ip(&mut self, address: usize){
+        self.ip=address;
+        self.checkpoint(true);
+    }
+
+    /// Records the execution for `reverse_step` and `reverse_continue`,
+    /// taking a checkpoint every `interval` instructions (doubled as the
+    /// run grows long, the number of checkpoints being bounded). Going back
+    /// re-executes from the checkpoint before the target. `None` (the
+    /// default) records nothing.
+    pub fn set_checkpoint_interval(&mut self, interval: Option<u64>){
+        self.history=interval.map(History::new);
+        self.checkpoint(true);
+    }
+
+    /// Sends the output of msg to `sink` as it executes instead of buffering
+    /// it; `output` and the `String` returned by `run` then stay empty.
+    /// Clones of the cpu share the sink. `None` restores the buffering.
+    pub fn set_output_sink(&mut self, sink: Option<SharedSink>){
+        self.sink=sink;
+    }
+
+    /// Records every executed instruction with `tracer`, `None` (the
+    /// default) to stop tracing. Clones of the cpu share the tracer.
+    pub fn set_tracer(&mut self, tracer: Option<SharedTracer>){
+        self.tracer=tracer;
+    }
+
+    /// Output of the msg instructions executed so far, unless an output
+    /// sink is set.
+    pub fn output(&self) -> &str{
+        &self.out
+    }
+
+    /// Result of the last cmp.
+    pub fn comparison(&self) -> Option<&Comparison>{
+        self.compare.as_ref()
+    }
+
+    /// Addresses of the pending calls, innermost last.
+    pub fn call_stack(&self) -> &[usize]{
+        &self.sub_calls
+    }
+
+    /// Registers used by the program with their value, in order of first use.
+    pub fn registers(&self) -> impl Iterator<Item=(&str, i64)> + '_{
+        self.program.decoded.reg_names.iter().map(String::as_str).zip(self.regs.iter().map(Register::get_value))
+    }
+
+    pub fn code(&self) -> &[Command]{
+        &self.program.code
+    }
+
+    pub fn label_address(&self, name: &str) -> Option<usize>{
+        self.program.label_address(name)
+    }
+
+    /// Value of a register, or `None` if the loaded program doesn't use it.
+    pub fn get_register_value(&self, r:&str) -> Option<i64>{
+        self.program.decoded.reg_index(r).map(|i| self.regs[i].get_value())
+    }
+
+    /// Sets a register before running, e.g. the input of the program.
+    pub fn set_register_value(&mut self, r:&str, val:i64) -> Result<(), String>{
+        let i=self.program.decoded.reg_index(r).ok_or_else(|| format!("Register {} is not used by the program", r))?;
+        self.regs[i].set_value(val);
+        self.written[i]=true;
+        self.checkpoint(true);
+        Ok(())
+    }
+
+    fn get_param_value(&self, p:Operand) -> i64{
+        match p {
+            Operand::Reg(r) => self.regs[r].get_value(),
+            Operand::Val(v) => v,
+        }
+    }
+
+}
+
+pub struct AssemblerInterpreter {
+}
+
+impl AssemblerInterpreter {
+    pub fn interpret(input: &str) -> Option<String> {
+        let mut cpu = Cpu::new();
+        cpu.load_code(input).ok()?;
+        cpu.run().ok()
+    }
+
+    /// Loads and runs `input`, reporting why it stopped along with its
+    /// output, final registers, instruction count and call depth.
+    pub fn execute(input: &str) -> ExecutionReport {
+        let mut cpu = Cpu::new();
+        match cpu.load_code(input) {
+            Ok(_) => cpu.execute(),
+            Err(diagnostics) => ExecutionReport::load_failed(diagnostics),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::Param;
+    use super::*;
+
+    use super::Cpu;
+    use super::Command;
+
+    fn init_cpu() -> Cpu {
+        Cpu::new()
+    }
+/*
+    #[test]
+    fn test_register_set_value() {
+        let mut register = Register { val: 0 };
+        register.set_value(42);
+        assert_eq!(register.val, 42);
+    }
+
+    #[test]
+    fn test_register_get_value() {
+        let register = Register { val: 42 };
+        assert_eq!(register.get_value(), 42);
+    }
+
+    #[test]
+    fn test_register_inc() {
+        let mut register = Register { val: 0 };
+        register.inc();
+        assert_eq!(register.val, 1);
+    }
+
+    #[test]
+    fn test_register_dec() {
+        let mut register = Register { val: 42 };
+        register.dec();
+        assert_eq!(register.val, 41);
+    }
+    
+    #[test]
+    fn test_register_mul() {
+        let mut register = Register { val: 42 };
+        register.mul(2);
+        assert_eq!(register.val, 84);
+    }
+    #[test]
+    fn test_register_div() {
+        let mut register = Register { val: 42 };
+        register.div(2);
+        assert_eq!(register.val, 21);
+    }
+    #[test]
+    fn test_register_add() {
+        let mut register = Register { val: 42 };
+        register.add(2);
+        assert_eq!(register.val, 44);
+    }
+    #[test]
+    fn test_register_sub() {
+        let mut register = Register { val: 42 };
+        register.sub(2);
+        assert_eq!(register.val, 40);
+    }
+    
+    #[test]
+    fn test_cpu_load_code_inc() {
+        let mut cpu = init_cpu();
+        cpu.load_code("inc a");
+        let reg=cpu.parse_register(&'a').unwrap();
+        assert_eq!(cpu.code[0], Command::Inc('a'));
+    }
+
+    
+    #[test]
+    fn test_cpu_load_code_from_vec() {
+        let mut cpu = init_cpu();
+        let code = ["mov a 5", "inc a", "dec a", "dec a", "jnz a -1", "inc a"];
+        cpu.load_code_from_vec(&code);
+        cpu.run();
+        assert_eq!(cpu.get_register_value(&'a'), Ok(1));
+    }
+    #[test]
+    fn test_cpu_operations() {
+        let mut cpu = init_cpu();
+        let code = ["mov a 5", "sub a 2", "add a 3", "mul a 2", "mov b a", "div a b"];
+        cpu.load_code_from_vec(&code);
+        cpu.run();
+        assert_eq!(cpu.get_register_value(&'a'), Ok(1));
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut cpu = init_cpu();
+        let code = ["coucou:", "mov a 3", "first:", "second:"];
+        let _ = cpu.load_code_from_vec(&code);
+        let _ = cpu.run();
+        assert_eq!(*cpu.labels.get(&"second".to_string()).unwrap(), 3 as usize);
+    }
+
+    #[test]
+    fn test_jump() {
+        let mut cpu = init_cpu();
+        let code = ["mov a 3", "coucou:", "dec a", "cmp a 0", "jne coucou", "end"];
+        let _ = cpu.load_code_from_vec(&code);
+        let _ = cpu.run();
+        assert_eq!(cpu.regs.get(&'a').unwrap().val, 0);
+    }
+    
+
+    #[test]
+    fn test_sub() {
+        let mut cpu = init_cpu();
+        let code = ["mov a 3", "coucou:","call sub_dec", "cmp a 0", "jne coucou", "end", "sub_dec:", "dec a", "ret"];
+        let _ = cpu.load_code_from_vec(&code);
+        let _ = cpu.run();
+        assert_eq!(cpu.regs.get(&'a').unwrap().val, 0);
+    }
+
+    #[test]
+    fn test_msg() {
+        let mut cpu = init_cpu();
+        let code = ["mov a 3", "mov b 2", "mov c 6", "msg   'mul(', a, ', ', b, ') = ', c        "];
+        let _ = cpu.load_code_from_vec(&code);
+        let out = cpu.run().unwrap();
+        assert_eq!(out, "mul(3, 2) = 6");
+    }
+    
+    #[test]
+    fn test_cw1() {
+        let mut cpu = init_cpu();
+        let code = "\n; My first program\nmov  a, 5\ninc  a\ncall function\nmsg  '(5+1)/2 = ', a    ; output message\nend\n\nfunction:\n    div  a, 2\n    ret\n";
+        let _ = cpu.load_code(&code);
+        let out = cpu.run().unwrap();
+        assert_eq!(out, "(5+1)/2 = 3");
+    }
+ */
+
+    #[test]
+    fn test_load_code_ok() {
+        let mut cpu = init_cpu();
+        cpu.load_code("mov a, 5\ninc a\nlbl:\njmp lbl").unwrap();
+        assert_eq!(cpu.code()[0], Command::Move("a".to_string(), Param::Val(5)));
+        assert_eq!(cpu.code()[1], Command::Inc("a".to_string()));
+    }
+
+    #[test]
+    fn test_load_code_errors() {
+        let err = |src: &str| init_cpu().load_code(src).unwrap_err().into_iter().find(Diagnostic::is_error).unwrap().error;
+
+        let e = err("mov a, 1\n  foo a\nend");
+        assert!(matches!(&e, AsmError::UnknownInstruction { mnemonic, .. } if mnemonic == "foo"));
+        assert_eq!(*e.pos(), SourcePos { line: 2, column: 3, snippet: "foo a".to_string() });
+
+        assert!(matches!(err("inc"), AsmError::OperandCount { expected: 1, found: 0, .. }));
+        assert!(matches!(err("mov 5, a"), AsmError::BadOperand { .. }));
+        assert!(matches!(err("l:\nl:"), AsmError::DuplicateLabel { first_line: 1, .. }));
+
+        let e = err("\njmp nowhere");
+        assert!(matches!(&e, AsmError::UndefinedLabel { name, .. } if name == "nowhere"));
+        assert_eq!(e.pos().column, 5);
+    }
+
+    #[test]
+    fn test_load_code_collects_diagnostics() {
+        let mut cpu = init_cpu();
+        let diagnostics = cpu.load_code("foo a\nmov a, 1\nunused:\nmov 1, a\njmp nowhere").unwrap_err();
+        let lines: Vec<(usize, Severity)> = diagnostics.iter().map(|d| (d.error.pos().line, d.severity)).collect();
+        assert_eq!(lines, vec![
+            (1, Severity::Error),
+            (3, Severity::Warning),
+            (4, Severity::Error),
+            (5, Severity::Warning),
+            (5, Severity::Error),
+        ]);
+        assert!(matches!(diagnostics[3].error, AsmError::MissingEnd { .. }));
+        // the good lines are still loaded
+        assert_eq!(cpu.code().len(), 3);
+
+        let warnings = init_cpu().load_code("mov a, 1\nend").unwrap();
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_run_registers() {
+        let mut cpu = init_cpu();
+        cpu.load_code("mov a, 5\nmov b, a\nmul b, 3\nsub b, 1\nend").unwrap();
+        assert_eq!(cpu.run(), Ok(String::new()));
+        assert_eq!(cpu.get_register_value("b"), Some(14));
+        assert_eq!(cpu.get_register_value("z"), None);
+    }
+
+    #[test]
+    fn test_named_registers() {
+        let program = "mov count, 3\nmov total, 0\nloop:\nadd total, count\ndec count\ncmp count, 0\njne loop\nmsg 'total = ', total\nend";
+        assert_eq!(AssemblerInterpreter::interpret(program), Some("total = 6".to_string()));
+
+        let mut cpu = init_cpu();
+        cpu.set_declared_registers(Some(&["count"]));
+        let diagnostics = cpu.load_code(program).unwrap_err();
+        assert!(matches!(&diagnostics[0].error, AsmError::UndeclaredRegister { name, .. } if name == "total"));
+        cpu.set_declared_registers(Some(&["count", "total"]));
+        assert!(cpu.load_code(program).is_ok());
+    }
+
+    #[test]
+    fn test_step_and_breakpoints() {
+        let mut cpu = init_cpu();
+        cpu.load_code("mov a, 5\ninc a\ncall function\nmsg '(5+1)/2 = ', a\nend\nfunction:\n    div  a, 2\n    ret").unwrap();
+
+        assert_eq!(cpu.step(), Ok(StepOutcome::Stepped));
+        assert_eq!(cpu.ip(), 1);
+        assert_eq!(cpu.set_breakpoint(&Breakpoint::Label("function".to_string())), Ok(5));
+        assert!(cpu.set_breakpoint(&Breakpoint::Label("nowhere".to_string())).is_err());
+
+        assert_eq!(cpu.resume(), Ok(StepOutcome::Breakpoint(5)));
+        assert_eq!(cpu.call_stack(), &[2]);
+        assert_eq!(cpu.registers().collect::<Vec<_>>(), vec![("a", 6)]);
+
+        assert_eq!(cpu.run_until(&Breakpoint::Address(3)), Ok(StepOutcome::Breakpoint(3)));
+        assert_eq!(cpu.get_register_value("a"), Some(3));
+        assert_eq!(cpu.output(), "");
+        assert_eq!(cpu.resume(), Ok(StepOutcome::End));
+        assert_eq!(cpu.output(), "(5+1)/2 = 3");
+        // a finished program stays on its `end`
+        assert_eq!(cpu.step(), Ok(StepOutcome::End));
+        assert_eq!(cpu.ip(), 4);
+
+        // run ignores the breakpoints, with or without history
+        cpu.set_register_value("a", 5).unwrap();
+        assert_eq!(cpu.run(), Ok("(5+1)/2 = 3".to_string()));
+        cpu.set_checkpoint_interval(Some(2));
+        cpu.set_register_value("a", 5).unwrap();
+        assert!(cpu.execute().succeeded());
+    }
+
+    #[test]
+    fn test_step_over_and_out() {
+        let mut cpu = init_cpu();
+        cpu.load_code("call f\nmsg a\nend\nf:\n  call g\n  inc a\n  ret\ng:\n  mov a, 5\n  ret").unwrap();
+        assert_eq!(cpu.step_over(), Ok(StepOutcome::Stepped));
+        assert_eq!((cpu.ip(), cpu.get_register_value("a")), (1, Some(6)));
+
+        cpu.set_ip(0);
+        assert_eq!(cpu.set_breakpoint(&Breakpoint::Line(9)), Ok(8));
+        assert_eq!(cpu.step_over(), Ok(StepOutcome::Breakpoint(8)));
+        assert_eq!(cpu.call_stack(), &[0, 4]);
+        assert_eq!(cpu.step_out(), Ok(StepOutcome::Stepped));
+        assert_eq!((cpu.ip(), cpu.call_stack()), (5, &[0][..]));
+        assert_eq!(cpu.step_out(), Ok(StepOutcome::Stepped));
+        assert_eq!(cpu.ip(), 1);
+        assert_eq!(cpu.step_out(), Ok(StepOutcome::End));
+        assert!(cpu.set_breakpoint(&Breakpoint::Line(11)).is_err());
+    }
+
+    #[test]
+    fn test_reverse_execution() {
+        let mut cpu = init_cpu();
+        cpu.load_code("mov a, 2000\nmov b, 0\nloop:\n  add b, a\n  dec a\n  jnz a, -2\nmsg b\nend").unwrap();
+        assert_eq!(cpu.reverse_step(), Err(RunError::NoHistory));
+        cpu.set_checkpoint_interval(Some(2));
+        let mut states = vec![cpu.snapshot()];
+        while cpu.step() == Ok(StepOutcome::Stepped) {
+            states.push(cpu.snapshot());
+        }
+        assert_eq!(cpu.output(), "2001000");
+        // the checkpoints were thinned out, every state can still be rebuilt
+        for state in states.iter().rev() {
+            assert_eq!(cpu.reverse_step(), Ok(StepOutcome::Stepped));
+            assert_eq!(&cpu.snapshot(), state);
+        }
+        assert_eq!(cpu.steps(), 0);
+
+        // a register changed by hand is kept by going back
+        cpu.set_breakpoint(&Breakpoint::Address(4)).unwrap();
+        cpu.resume().unwrap();
+        cpu.resume().unwrap();
+        cpu.set_register_value("b", -1).unwrap();
+        cpu.resume().unwrap();
+        assert_eq!(cpu.get_register_value("b"), Some(-1 + 1998));
+        assert_eq!(cpu.reverse_continue(), Ok(StepOutcome::Breakpoint(4)));
+        assert_eq!((cpu.get_register_value("a"), cpu.get_register_value("b")), (Some(1999), Some(-1)));
+        assert_eq!(cpu.reverse_continue(), Ok(StepOutcome::Breakpoint(4)));
+        assert_eq!(cpu.reverse_continue(), Ok(StepOutcome::Stepped));
+        assert_eq!(&cpu.snapshot(), &states[0]);
+
+        // the instructions executed again aren't sent to the sink
+        let lines = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut cpu = init_cpu();
+        cpu.set_checkpoint_interval(Some(100));
+        cpu.load_code("mov a, 3\nloop:\n  msg a\n  dec a\n  jnz a, -2\nend").unwrap();
+        cpu.set_output_sink(Some(lines.clone()));
+        cpu.set_breakpoint(&Breakpoint::Address(2)).unwrap();
+        cpu.resume().unwrap();
+        cpu.resume().unwrap();
+        cpu.resume().unwrap();
+        assert_eq!(*lines.lock().unwrap(), vec!["3", "2"]);
+        assert_eq!(cpu.reverse_continue(), Ok(StepOutcome::Breakpoint(2)));
+        assert_eq!(cpu.reverse_step(), Ok(StepOutcome::Stepped));
+        assert_eq!(*lines.lock().unwrap(), vec!["3", "2"]);
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        let source = "mov a, 10\ncall f\ndec a\njnz a, -2\nmsg 'b = ', b\nend\nf:\n  add b, a\n  cmp b, 30\n  ret";
+        let program = Arc::new(Program::load(source).unwrap());
+        let mut whole = program.cpu();
+        let expected = whole.execute();
+
+        let mut cpu = program.cpu();
+        cpu.set_max_steps(Some(17));
+        assert!(matches!(cpu.run(), Err(RunError::BudgetExhausted { steps: 17, .. })));
+        assert!(!cpu.call_stack().is_empty() && cpu.comparison().is_some());
+        let saved = cpu.save_state().to_json().to_string();
+
+        let state = CpuState::from_json(&serde_json::from_str(&saved).unwrap()).unwrap();
+        let mut resumed = program.cpu();
+        resumed.load_state(&state).unwrap();
+        assert_eq!(resumed.save_state().to_json(), cpu.save_state().to_json());
+        assert_eq!(resumed.resume(), Ok(StepOutcome::End));
+        assert_eq!((resumed.output(), resumed.steps()), (expected.output.as_str(), expected.steps));
+        assert_eq!(resumed.registers().collect::<Vec<_>>(), whole.registers().collect::<Vec<_>>());
+
+        let mut other = Cpu::new();
+        other.load_code("mov a, 10\nend").unwrap();
+        assert!(other.load_state(&state).is_err());
+        assert!(CpuState::from_json(&serde_json::json!({ "version": 2 })).is_err());
+        let mut json = state.to_json();
+        json["comparison"] = serde_json::json!(1);
+        assert_eq!(CpuState::from_json(&json), Err("bad `comparison`".to_string()));
+        json["comparison"] = serde_json::Value::Null;
+        assert_eq!(CpuState::from_json(&json).map(|s| s.comparison), Ok(None));
+
+        // a crash dump points at the faulting instruction
+        let mut cpu = init_cpu();
+        cpu.load_code("mov a, 1\nmov b, 0\ndiv a, b\nend").unwrap();
+        assert!(cpu.run().is_err());
+        let dump = cpu.save_state();
+        assert_eq!((dump.ip, dump.registers.clone()), (2, vec![("a".to_string(), 1), ("b".to_string(), 0)]));
+
+        // the written registers are only known when unwritten reads fault
+        assert_eq!(dump.written, None);
+        let mut strict = init_cpu();
+        strict.set_uninitialized_reads(UninitializedReads::Fault);
+        strict.load_code("mov a, 1\nmov b, 0\ndiv a, b\nend").unwrap();
+        assert!(strict.load_state(&dump).is_err());
+        assert!(strict.run().is_err());
+        let dump = strict.save_state();
+        assert_eq!(dump.written, Some(vec!["a".to_string(), "b".to_string()]));
+        assert_eq!(CpuState::from_json(&dump.to_json()), Ok(dump.clone()));
+        strict.load_state(&dump).unwrap();
+    }
+
+    #[test]
+    fn test_command_display() {
+        let source = "mov a, -5\njnz a, 2\nloop:\ncall loop\nmsg 'it\\'s ', a, 3\nend";
+        let mut cpu = init_cpu();
+        cpu.load_code(source).unwrap();
+        let lines: Vec<String> = cpu.code().iter().map(|c| c.to_string()).collect();
+        assert_eq!(lines.join("\n"), source);
+    }
+
+    #[test]
+    fn test_instruction_budget() {
+        let mut cpu = init_cpu();
+        cpu.load_code("mov a, 1\nl:\njmp l\nend").unwrap();
+        cpu.set_max_steps(Some(100));
+        assert_eq!(cpu.run(), Err(RunError::BudgetExhausted { ip: 2, steps: 100 }));
+        assert_eq!(cpu.steps(), 100);
+
+        // run starts a new count
+        cpu.load_code("mov a, 1\nend").unwrap();
+        assert_eq!(cpu.run(), Ok(String::new()));
+        assert_eq!(cpu.steps(), 2);
+    }
+
+    #[test]
+    fn test_cancel_and_deadline() {
+        let program = "msg 'started'\nl:\njmp l\nend";
+        let mut cpu = init_cpu();
+        cpu.load_code(program).unwrap();
+        let token = CancelToken::new();
+        cpu.set_cancel_token(Some(token.clone()));
+        let handle = std::thread::spawn(move || cpu.run());
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        token.cancel();
+        match handle.join().unwrap() {
+            Err(RunError::Cancelled { ip, output, .. }) => {
+                assert_eq!(ip, 2);
+                assert_eq!(output, "started");
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let mut cpu = init_cpu();
+        cpu.load_code(program).unwrap();
+        cpu.set_deadline(Some(Instant::now() + std::time::Duration::from_millis(20)));
+        assert!(matches!(cpu.run(), Err(RunError::TimedOut { output, .. }) if output == "started"));
+    }
+
+    #[test]
+    fn test_arithmetic_modes() {
+        let run = |mode: ArithmeticMode, program: &str| {
+            let mut cpu = init_cpu();
+            cpu.load_code(program).unwrap();
+            cpu.set_arithmetic_mode(mode);
+            cpu.run().map(|_| cpu.get_register_value("a").unwrap())
+        };
+        let program = "mov a, 9223372036854775807\ninc a\nend";
+        assert_eq!(run(ArithmeticMode::Wrapping, program), Ok(i64::MIN));
+        assert_eq!(run(ArithmeticMode::Saturating, program), Ok(i64::MAX));
+        assert_eq!(run(ArithmeticMode::Checked, program).unwrap_err().to_string(), "arithmetic overflow at address 1, line 2: `inc a` [a=9223372036854775807]");
+
+        let program = "mov a, -9223372036854775807\nmul a, 2\nend";
+        assert_eq!(run(ArithmeticMode::Wrapping, program), Ok(2));
+        assert_eq!(run(ArithmeticMode::Saturating, program), Ok(i64::MIN));
+        assert!(run(ArithmeticMode::Checked, program).is_err());
+
+        let program = "mov a, 1\njnz a, 9223372036854775807\nend";
+        assert!(matches!(run(ArithmeticMode::Checked, program), Err(RunError::Fault(e)) if e.kind == FaultKind::Overflow));
+        assert!(matches!(run(ArithmeticMode::Saturating, program), Err(RunError::Fault(e)) if e.kind == FaultKind::BadJump { offset: i64::MAX }));
+    }
+
+    #[test]
+    fn test_division_faults() {
+        let mut cpu = init_cpu();
+        cpu.load_code("mov a, 7\nmov b, 0\n\n  div a, b ; oops\nend").unwrap();
+        let err = match cpu.run() {
+            Err(RunError::Fault(err)) => err,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(err.kind, FaultKind::DivisionByZero);
+        assert_eq!(err.address, 2);
+        assert_eq!(err.pos.as_ref().map(|pos| pos.line), Some(4));
+        assert_eq!(err.registers, vec![("a".to_string(), 7), ("b".to_string(), 0)]);
+        assert_eq!(err.to_string(), "division by zero at address 2, line 4: `div a, b ; oops` [a=7 b=0]");
+
+        cpu.load_code("mov a, -9223372036854775808\ndiv a, -1\nend").unwrap();
+        cpu.set_arithmetic_mode(ArithmeticMode::Checked);
+        assert!(matches!(cpu.run(), Err(RunError::Fault(e)) if e.kind == FaultKind::DivisionOverflow));
+        cpu.set_arithmetic_mode(ArithmeticMode::Wrapping);
+        assert!(cpu.run().is_ok());
+        assert_eq!(cpu.get_register_value("a"), Some(i64::MIN));
+    }
+
+    #[test]
+    fn test_fault_handler() {
+        let program = "mov a, 10\nmov b, 0\ndiv a, b\nmsg 'a = ', a\nend\non_fault:\nmov a, -1\nret";
+        let mut cpu = init_cpu();
+        cpu.load_code(program).unwrap();
+        assert!(cpu.set_fault_handler(Some("on_falt")).is_err());
+        cpu.set_fault_handler(Some("on_fault")).unwrap();
+        assert_eq!(cpu.run(), Ok("a = -1".to_string()));
+
+        cpu.load_code("div a, 0\nend\nh:\ndiv a, 0\nret").unwrap();
+        cpu.set_fault_handler(Some("h")).unwrap();
+        match cpu.run() {
+            Err(RunError::Fault(e)) => {
+                assert_eq!(e.kind, FaultKind::CallDepthExceeded);
+                assert_eq!(e.address, 3);
+                assert_eq!(e.backtrace.len(), DEFAULT_MAX_CALL_DEPTH);
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_call_stack_faults() {
+        let mut cpu = init_cpu();
+        cpu.load_code("msg 'x'\nret\nend").unwrap();
+        assert!(matches!(cpu.run(), Err(RunError::Fault(e)) if e.kind == FaultKind::ReturnWithoutCall && e.address == 1));
+        cpu.set_empty_return(EmptyReturn::End);
+        assert_eq!(cpu.run(), Ok("x".to_string()));
+
+        let program = "call main\nend\nmain:\n  call rec\n  ret\nrec:\n  inc a\n  call rec\n  ret";
+        cpu.load_code(program).unwrap();
+        cpu.set_max_call_depth(Some(4));
+        let err = match cpu.run() {
+            Err(RunError::Fault(err)) => err,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(err.kind, FaultKind::CallDepthExceeded);
+        assert_eq!(err.address, 7);
+        assert_eq!(cpu.get_register_value("a"), Some(3));
+        let frames: Vec<(usize, Option<&str>)> = err.backtrace.iter().map(|f| (f.address, f.label.as_deref())).collect();
+        assert_eq!(frames, vec![(7, Some("rec")), (7, Some("rec")), (3, Some("main")), (0, None)]);
+        assert_eq!(err.backtrace[2].to_string(), "called from address 3 in main, line 4");
+        assert_eq!(err.backtrace[3].to_string(), "called from address 0, line 1");
+        assert!(err.to_string().ends_with("\n    called from address 7 in rec, line 8 (2 times)\n    called from address 3 in main, line 4\n    called from address 0, line 1"));
+
+        // a runaway recursion is summed up
+        cpu.load_code("f:\ncall f\nend").unwrap();
+        cpu.set_max_call_depth(Some(DEFAULT_MAX_CALL_DEPTH));
+        let err = cpu.run().unwrap_err().to_string();
+        assert_eq!(err.lines().count(), 2);
+        assert!(err.ends_with("called from address 1 in f, line 2 (100000 times)"));
+        let frames: Vec<Frame> = (0..30).map(|address| Frame { address, label: None, line: None }).collect();
+        let shown = Backtrace(&frames).to_string();
+        assert_eq!(shown.lines().count(), 21);
+        assert!(shown.ends_with("\n    ... 10 more frames"));
+    }
+
+    #[test]
+    fn test_uninitialized_reads() {
+        let program = "mov a, 2\nmul a, b\nmsg a\nend";
+        let mut cpu = init_cpu();
+        cpu.load_code(program).unwrap();
+        assert_eq!(cpu.run(), Ok("0".to_string()));
+
+        cpu.set_uninitialized_reads(UninitializedReads::Fault);
+        cpu.load_code(program).unwrap();
+        let err = match cpu.run() {
+            Err(RunError::Fault(err)) => err,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(err.kind, FaultKind::UninitializedRegister { name: "b".to_string() });
+        assert_eq!(err.to_string(), "read of uninitialized register `b` at address 1, line 2: `mul a, b` [a=2 b=0]");
+        cpu.load_code(program).unwrap();
+        cpu.set_register_value("b", 3).unwrap();
+        assert_eq!(cpu.run(), Ok("6".to_string()));
+
+        cpu.set_uninitialized_reads(UninitializedReads::Warn);
+        let warnings: Vec<String> = cpu.load_code(program).unwrap().iter().map(|d| d.to_string()).collect();
+        assert_eq!(warnings, vec!["warning: register `b` may be read before it is written at 2:8: `mul a, b`"]);
+    }
+
+    #[test]
+    fn test_execute() {
+        let report = AssemblerInterpreter::execute("mov a, 2\ncall f\nmsg 'a = ', a\nend\nf:\ncall g\nret\ng:\ninc a\nret");
+        assert_eq!(report, ExecutionReport {
+            output: "a = 3".to_string(),
+            termination: Termination::End,
+            registers: vec![("a".to_string(), 3)],
+            steps: 10, // labels count as instructions
+            max_call_depth: 2,
+        });
+
+        let report = AssemblerInterpreter::execute("msg 'half'\nmov a, 1\ndiv a, 0\nend");
+        assert!(matches!(&report.termination, Termination::Failed(RunError::Fault(e)) if e.kind == FaultKind::DivisionByZero));
+        assert_eq!(report.output, "half");
+        assert_eq!(report.steps, 3);
+        assert_eq!(report.clone().into_result(), None);
+
+        assert!(matches!(AssemblerInterpreter::execute("inc a").termination, Termination::Failed(RunError::NoEnd)));
+        assert!(matches!(AssemblerInterpreter::execute("jmp nowhere\nend").termination, Termination::LoadFailed(_)));
+    }
+
+    #[test]
+    fn test_reuse_cpu() {
+        // counts down from a to 0, then faults inside f if b is 0
+        let program = "call f\nmsg 'done ', a\nend\nf:\ndec a\njnz a, -1\ndiv a, b\nret";
+        let mut cpu = init_cpu();
+        cpu.load_code(program).unwrap();
+        cpu.set_register_value("a", 3).unwrap();
+        assert!(matches!(cpu.run(), Err(RunError::Fault(e)) if e.backtrace.len() == 1));
+        // the pending call of the failed run is gone
+        cpu.set_register_value("a", 2).unwrap();
+        cpu.set_register_value("b", 1).unwrap();
+        assert_eq!(cpu.run(), Ok("done 0".to_string()));
+        assert!(cpu.call_stack().is_empty());
+
+        cpu.reset();
+        assert_eq!(cpu.registers().collect::<Vec<_>>(), vec![("a", 0), ("b", 0)]);
+        assert_eq!(cpu.output(), "");
+
+        let shared = cpu.program().clone();
+        let mut other = shared.cpu();
+        other.set_register_value("a", 1).unwrap();
+        other.set_register_value("b", 1).unwrap();
+        assert_eq!(other.run(), Ok("done 0".to_string()));
+        assert!(Arc::ptr_eq(other.program(), cpu.program()));
+    }
+
+    #[test]
+    fn test_jnz_out_of_code() {
+        let mut cpu = init_cpu();
+        cpu.load_code("mov a, 1\njnz a, -5\nend").unwrap();
+        assert!(matches!(cpu.run(), Err(RunError::Fault(e)) if e.kind == FaultKind::BadJump { offset: -5 }));
+        cpu.load_code("mov a, 1\njnz a, 9223372036854775807\nend").unwrap();
+        assert!(matches!(cpu.run(), Err(RunError::Fault(_))));
+        cpu.load_code("mov a, 1\njnz a, 2\nend").unwrap();
+        assert_eq!(cpu.run(), Err(RunError::NoEnd));
+    }
+
+    #[test]
+    fn simple_test() {
+        let simple_programs = &[
+            "\n; My first program\nmov  a, 5\ninc  a\ncall function\nmsg  '(5+1)/2 = ', a    ; output message\nend\n\nfunction:\n    div  a, 2\n    ret\n",
+            "\nmov   a, 5\nmov   b, a\nmov   c, a\ncall  proc_fact\ncall  print\nend\n\nproc_fact:\n    dec   b\n    mul   c, b\n    cmp   b, 1\n    jne   proc_fact\n    ret\n\nprint:\n    msg   a, '! = ', c ; output text\n    ret\n",
+            "\nmov   a, 8            ; value\nmov   b, 0            ; next\nmov   c, 0            ; counter\nmov   d, 0            ; first\nmov   e, 1            ; second\ncall  proc_fib\ncall  print\nend\n\nproc_fib:\n    cmp   c, 2\n    jl    func_0\n    mov   b, d\n    add   b, e\n    mov   d, e\n    mov   e, b\n    inc   c\n    cmp   c, a\n    jle   proc_fib\n    ret\n\nfunc_0:\n    mov   b, c\n    inc   c\n    jmp   proc_fib\n\nprint:\n    msg   'Term ', a, ' of Fibonacci series is: ', b        ; output text\n    ret\n",
+            "\nmov   a, 11           ; value1\nmov   b, 3            ; value2\ncall  mod_func\nmsg   'mod(', a, ', ', b, ') = ', d        ; output\nend\n\n; Mod function\nmod_func:\n    mov   c, a        ; temp1\n    div   c, b\n    mul   c, b\n    mov   d, a        ; temp2\n    sub   d, c\n    ret\n",
+            "\nmov   a, 81         ; value1\nmov   b, 153        ; value2\ncall  init\ncall  proc_gcd\ncall  print\nend\n\nproc_gcd:\n    cmp   c, d\n    jne   loop\n    ret\n\nloop:\n    cmp   c, d\n    jg    a_bigger\n    jmp   b_bigger\n\na_bigger:\n    sub   c, d\n    jmp   proc_gcd\n\nb_bigger:\n    sub   d, c\n    jmp   proc_gcd\n\ninit:\n    cmp   a, 0\n    jl    a_abs\n    cmp   b, 0\n    jl    b_abs\n    mov   c, a            ; temp1\n    mov   d, b            ; temp2\n    ret\n\na_abs:\n    mul   a, -1\n    jmp   init\n\nb_abs:\n    mul   b, -1\n    jmp   init\n\nprint:\n    msg   'gcd(', a, ', ', b, ') = ', c\n    ret\n",
+            "\ncall  func1\ncall  print\nend\n\nfunc1:\n    call  func2\n    ret\n\nfunc2:\n    ret\n\nprint:\n    msg 'This program should return null'\n",
+            "\nmov   a, 2            ; value1\nmov   b, 10           ; value2\nmov   c, a            ; temp1\nmov   d, b            ; temp2\ncall  proc_func\ncall  print\nend\n\nproc_func:\n    cmp   d, 1\n    je    continue\n    mul   c, a\n    dec   d\n    call  proc_func\n\ncontinue:\n    ret\n\nprint:\n    msg a, '^', b, ' = ', c\n    ret\n"];
+
+        let expected = &[
+            Some(String::from("(5+1)/2 = 3")),
+            Some(String::from("5! = 120")),
+            Some(String::from("Term 8 of Fibonacci series is: 21")),
+            Some(String::from("mod(11, 3) = 2")),
+            Some(String::from("gcd(81, 153) = 9")),
+            None,
+            Some(String::from("2^10 = 1024"))];
+
+        for (prg, exp) in simple_programs.iter().zip(expected) {
+            let actual = AssemblerInterpreter::interpret(prg);
+            assert_eq!(actual, *exp);
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,89 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::error::{AsmError, Diagnostic, SourcePos};
+use crate::lexer::Span;
+use crate::parser::Statement;
+
+/// Second pass of the loader: records the address of every label, then
+/// resolves the label of each jump and call into its target address.
+/// `source` holds the program lines, used for the diagnostics snippets.
+pub fn link(statements: &mut [Statement], source: &[&str]) -> (HashMap<String, usize>, Vec<Diagnostic>) {
+    let pos = |span: Span| SourcePos::new(span, source[span.line - 1]);
+    let mut diagnostics = Vec::new();
+    let mut labels = HashMap::new();
+    let mut defs: Vec<(String, Span)> = Vec::new();
+
+    for (address, stmt) in statements.iter().enumerate() {
+        if let crate::Command::Label(name) = &stmt.command {
+            match defs.iter().find(|(def, _)| def == name) {
+                Some((_, first)) => diagnostics.push(Diagnostic::error(AsmError::DuplicateLabel {
+                    pos: pos(stmt.span),
+                    name: name.clone(),
+                    first_line: first.line,
+                })),
+                None => {
+                    defs.push((name.clone(), stmt.span));
+                    labels.insert(name.clone(), address);
+                }
+            }
+        }
+    }
+
+    let mut used = HashSet::new();
+    for stmt in statements.iter_mut() {
+        let operand = stmt.operands.first().copied();
+        if let Some(target) = stmt.command.target_mut() {
+            match labels.get(&target.label) {
+                Some(address) => target.address = Some(*address),
+                None => diagnostics.push(Diagnostic::error(AsmError::UndefinedLabel {
+                    pos: pos(operand.unwrap_or(stmt.span)),
+                    name: target.label.clone(),
+                })),
+            }
+            used.insert(target.label.clone());
+        }
+    }
+
+    for (name, span) in defs {
+        if !used.contains(&name) {
+            diagnostics.push(Diagnostic::warning(AsmError::UnusedLabel { pos: pos(span), name }));
+        }
+    }
+    (labels, diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_line;
+    use crate::{Command, Target};
+
+    fn statements(source: &[&str]) -> Vec<Statement> {
+        source.iter().enumerate().filter_map(|(i, line)| parse_line(i + 1, line, None).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_link_resolves_targets() {
+        let source = ["jmp done", "inc a", "done:", "call done", "end"];
+        let mut stmts = statements(&source);
+        let (labels, diagnostics) = link(&mut stmts, &source);
+        assert!(diagnostics.is_empty());
+        assert_eq!(labels["done"], 2);
+        assert_eq!(stmts[0].command, Command::Jmp(Target { label: "done".to_string(), address: Some(2) }));
+        assert_eq!(stmts[3].command.target().unwrap().address, Some(2));
+    }
+
+    #[test]
+    fn test_link_label_errors() {
+        let source = ["a:", "jmp a", "a:", "jne b", "c:"];
+        let mut stmts = statements(&source);
+        let (labels, diagnostics) = link(&mut stmts, &source);
+        assert_eq!(labels["a"], 0);
+        let errors: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(errors, vec![
+            "error: label `a` already defined on line 1 at 3:1: `a:`",
+            "error: undefined label `b` at 4:5: `jne b`",
+            "warning: label `c` is never used at 5:1: `c:`",
+        ]);
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use serde_json::{json, Value};
+
+use crate::error::{Diagnostic, Severity};
+use crate::framing::{read_message, write_message};
+use crate::lexer::Span;
+use crate::program::Program;
+use crate::{parser, Command};
+
+/// `CompletionItemKind` of a mnemonic.
+const KEYWORD_ITEM: i64 = 14;
+/// `CompletionItemKind` of a label.
+const FUNCTION_ITEM: i64 = 3;
+/// `SymbolKind` of a label.
+const FUNCTION_SYMBOL: i64 = 12;
+
+/// Language Server Protocol server for assembly programs, see `serve_lsp`.
+///
+/// Documents are synchronized in full. Positions are counted in chars,
+/// which matches the UTF-16 columns of the client for ASCII sources.
+pub struct LspServer<W: Write> {
+    out: W,
+    documents: HashMap<String, String>, // uri -> text
+}
+
+/// Serves LSP requests read from `input` until `exit` or the end of the
+/// input.
+pub fn serve_lsp(mut input: impl BufRead, output: impl Write) -> io::Result<()> {
+    let mut server = LspServer::new(output);
+    while let Some(message) = read_message(&mut input)? {
+        match message {
+            Ok(message) => {
+                if !server.handle(&message)? {
+                    break;
+                }
+            }
+            Err(e) => server.parse_error(&e)?,
+        }
+    }
+    Ok(())
+}
+
+/// What a source line holds at some position.
+enum Symbol<'a> {
+    Mnemonic(&'a str),
+    Label(&'a str), // a definition or a jump/call target
+}
+
+/// The labels of a document and the places they are used, and the
+/// mnemonic of every instruction.
+#[derive(Default)]
+struct Index {
+    definitions: Vec<(String, Span)>,
+    references: Vec<(String, Span)>,
+    mnemonics: Vec<(&'static str, Span)>,
+}
+
+impl Index {
+    /// Indexes the lines that parse, ignoring the others.
+    fn new(text: &str) -> Self {
+        let mut index = Index::default();
+        for (i, raw) in text.lines().enumerate() {
+            let Ok(Some(stmt)) = parser::parse_line(i + 1, raw, None) else { continue };
+            match &stmt.command {
+                Command::Label(name) => index.definitions.push((name.clone(), stmt.span)),
+                command => {
+                    index.mnemonics.push((command.mnemonic(), stmt.span));
+                    if let (Some(target), Some(span)) = (command.target(), stmt.operands.first()) {
+                        index.references.push((target.label.clone(), *span));
+                    }
+                }
+            }
+        }
+        index
+    }
+
+    fn at(&self, line: usize, column: usize) -> Option<(Symbol<'_>, Span)> {
+        let contains = |span: &Span| span.line == line && (span.column..span.column + span.len).contains(&column);
+        let labels = self.definitions.iter().chain(&self.references);
+        if let Some((name, span)) = labels.into_iter().find(|(_, span)| contains(span)) {
+            return Some((Symbol::Label(name), *span));
+        }
+        self.mnemonics.iter().find(|(_, span)| contains(span)).map(|(m, span)| (Symbol::Mnemonic(m), *span))
+    }
+}
+
+impl<W: Write> LspServer<W> {
+    pub fn new(out: W) -> Self {
+        LspServer { out, documents: HashMap::new() }
+    }
+
+    /// Handles one request or notification. Returns false on `exit`.
+    pub fn handle(&mut self, message: &Value) -> io::Result<bool> {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let result = match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": {},
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": { "name": "simple_assembler_code_wars" },
+            })),
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.documents.insert(uri.to_string(), text.to_string());
+                return self.publish_diagnostics(uri).map(|_| true);
+            }
+            "textDocument/didChange" => {
+                let changes = params["contentChanges"].as_array();
+                if let Some(text) = changes.and_then(|c| c.last()).and_then(|c| c["text"].as_str()) {
+                    self.documents.insert(uri.to_string(), text.to_string());
+                }
+                return self.publish_diagnostics(uri).map(|_| true);
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                self.notify("textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": [] }))?;
+                return Ok(true);
+            }
+            "textDocument/definition" => Ok(self.definition(uri, &params["position"])),
+            "textDocument/references" => {
+                let declaration = params["context"]["includeDeclaration"].as_bool().unwrap_or(true);
+                Ok(self.references(uri, &params["position"], declaration))
+            }
+            "textDocument/hover" => Ok(self.hover(uri, &params["position"])),
+            "textDocument/completion" => Ok(self.completion(uri)),
+            "textDocument/documentSymbol" => Ok(self.symbols(uri)),
+            "shutdown" => Ok(Value::Null),
+            "exit" => return Ok(false),
+            _ => Err(format!("unsupported method `{}`", method)),
+        };
+        if message.get("id").is_none() {
+            return Ok(true); // notifications get no response
+        }
+        let mut response = json!({ "jsonrpc": "2.0", "id": message["id"] });
+        match result {
+            Ok(result) => response["result"] = result,
+            Err(message) => response["error"] = json!({ "code": -32601, "message": message }),
+        }
+        write_message(&mut self.out, &response)?;
+        Ok(true)
+    }
+
+    /// Answers a message that isn't valid JSON, whose id is unknown.
+    pub fn parse_error(&mut self, error: &str) -> io::Result<()> {
+        let message = format!("invalid JSON: {}", error);
+        write_message(&mut self.out, &json!({ "jsonrpc": "2.0", "id": null, "error": { "code": -32700, "message": message } }))
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str) -> io::Result<()> {
+        let text = self.documents.get(uri).map_or("", String::as_str);
+        let lines: Vec<&str> = text.lines().collect();
+        let (_, diagnostics) = Program::build(text, None, false);
+        let diagnostics: Vec<Value> = diagnostics.iter().map(|d| diagnostic(d, &lines)).collect();
+        self.notify("textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": diagnostics }))
+    }
+
+    /// The document of `uri` indexed, and the 1-based line and column of
+    /// an LSP position in it.
+    fn locate(&self, uri: &str, position: &Value) -> Option<(Index, usize, usize)> {
+        let text = self.documents.get(uri)?;
+        let line = position["line"].as_u64()? as usize + 1;
+        let column = position["character"].as_u64()? as usize + 1;
+        Some((Index::new(text), line, column))
+    }
+
+    fn definition(&self, uri: &str, position: &Value) -> Value {
+        let Some((index, line, column)) = self.locate(uri, position) else { return Value::Null };
+        let Some((Symbol::Label(name), _)) = index.at(line, column) else { return Value::Null };
+        let locations: Vec<Value> =
+            index.definitions.iter().filter(|(n, _)| n == name).map(|(_, span)| location(uri, *span)).collect();
+        json!(locations)
+    }
+
+    fn references(&self, uri: &str, position: &Value, declaration: bool) -> Value {
+        let Some((index, line, column)) = self.locate(uri, position) else { return Value::Null };
+        let Some((Symbol::Label(name), _)) = index.at(line, column) else { return Value::Null };
+        let definitions = index.definitions.iter().filter(|_| declaration);
+        let mut spans: Vec<Span> =
+            definitions.chain(&index.references).filter(|(n, _)| n == name).map(|(_, span)| *span).collect();
+        spans.sort_by_key(|span| (span.line, span.column));
+        json!(spans.into_iter().map(|span| location(uri, span)).collect::<Vec<_>>())
+    }
+
+    fn hover(&self, uri: &str, position: &Value) -> Value {
+        let Some((index, line, column)) = self.locate(uri, position) else { return Value::Null };
+        let contents = match index.at(line, column) {
+            Some((Symbol::Mnemonic(mnemonic), span)) => match Command::doc(mnemonic) {
+                Some(doc) => (doc.to_string(), span),
+                None => return Value::Null,
+            },
+            Some((Symbol::Label(name), span)) => {
+                let lines: Vec<String> = index
+                    .definitions
+                    .iter()
+                    .filter(|(n, _)| n == name)
+                    .map(|(_, def)| format!("label `{}`, line {}", name, def.line))
+                    .collect();
+                if lines.is_empty() {
+                    (format!("undefined label `{}`", name), span)
+                } else {
+                    (lines.join("\n\n"), span)
+                }
+            }
+            None => return Value::Null,
+        };
+        json!({ "contents": { "kind": "markdown", "value": contents.0 }, "range": range(contents.1) })
+    }
+
+    fn completion(&self, uri: &str) -> Value {
+        let mut items: Vec<Value> = Command::MNEMONICS
+            .iter()
+            .map(|mnemonic| json!({ "label": mnemonic, "kind": KEYWORD_ITEM, "documentation": Command::doc(mnemonic) }))
+            .collect();
+        let index = Index::new(self.documents.get(uri).map_or("", String::as_str));
+        for (name, span) in &index.definitions {
+            items.push(json!({ "label": name, "kind": FUNCTION_ITEM, "detail": format!("label, line {}", span.line) }));
+        }
+        json!(items)
+    }
+
+    fn symbols(&self, uri: &str) -> Value {
+        let index = Index::new(self.documents.get(uri).map_or("", String::as_str));
+        let symbols: Vec<Value> = index
+            .definitions
+            .iter()
+            .map(|(name, span)| {
+                json!({ "name": name, "kind": FUNCTION_SYMBOL, "range": range(*span), "selectionRange": range(*span) })
+            })
+            .collect();
+        json!(symbols)
+    }
+
+    fn notify(&mut self, method: &str, params: Value) -> io::Result<()> {
+        write_message(&mut self.out, &json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+    }
+}
+
+fn range(span: Span) -> Value {
+    let (line, start) = (span.line - 1, span.column - 1);
+    json!({
+        "start": { "line": line, "character": start },
+        "end": { "line": line, "character": start + span.len },
+    })
+}
+
+fn location(uri: &str, span: Span) -> Value {
+    json!({ "uri": uri, "range": range(span) })
+}
+
+/// An LSP diagnostic covering the token at the position of `d`, up to the
+/// next separator.
+fn diagnostic(d: &Diagnostic, lines: &[&str]) -> Value {
+    let pos = d.error.pos();
+    let raw = lines.get(pos.line - 1).copied().unwrap_or_default();
+    let token = raw.chars().skip(pos.column - 1).take_while(|c| !c.is_whitespace() && !",:;".contains(*c)).count();
+    let span = Span { line: pos.line, column: pos.column, len: token.max(1) };
+    let severity = match d.severity {
+        Severity::Error => 1,
+        Severity::Warning => 2,
+    };
+    json!({ "range": range(span), "severity": severity, "source": "asm", "message": d.error.message() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::tests::read_all;
+
+    #[test]
+    fn test_lsp_session() {
+        let uri = "file:///prog.asm";
+        let text = "mov a, 2\ncall double\njmp done\ndouble:\n  add a, a\n  ret\ndone:\nend\nfoo b\n";
+        let mut input = Vec::new();
+        let messages = [
+            json!({ "id": 1, "method": "initialize", "params": {} }),
+            json!({ "method": "textDocument/didOpen", "params": { "textDocument": { "uri": uri, "text": text } } }),
+            json!({ "id": 2, "method": "textDocument/definition", "params": { "textDocument": { "uri": uri }, "position": { "line": 1, "character": 7 } } }),
+            json!({ "id": 3, "method": "textDocument/references", "params": { "textDocument": { "uri": uri }, "position": { "line": 3, "character": 0 }, "context": { "includeDeclaration": true } } }),
+            json!({ "id": 4, "method": "textDocument/hover", "params": { "textDocument": { "uri": uri }, "position": { "line": 4, "character": 3 } } }),
+            json!({ "id": 5, "method": "textDocument/completion", "params": { "textDocument": { "uri": uri }, "position": { "line": 0, "character": 0 } } }),
+            json!({ "id": 6, "method": "textDocument/documentSymbol", "params": { "textDocument": { "uri": uri } } }),
+            json!({ "id": 7, "method": "shutdown" }),
+            json!({ "method": "exit" }),
+        ];
+        for message in &messages {
+            write_message(&mut input, message).unwrap();
+        }
+        let mut out = Vec::new();
+        serve_lsp(&input[..], &mut out).unwrap();
+        let responses = read_all(&out);
+        assert_eq!(responses.len(), 8);
+
+        assert_eq!(responses[0]["result"]["capabilities"]["definitionProvider"], true);
+        let diagnostics = &responses[1]["params"]["diagnostics"];
+        assert_eq!(diagnostics.as_array().unwrap().len(), 1);
+        assert_eq!(diagnostics[0]["message"], "unknown instruction `foo`");
+        assert_eq!(diagnostics[0]["range"]["end"], json!({ "line": 8, "character": 3 }));
+        assert_eq!(responses[2]["result"][0]["range"]["start"], json!({ "line": 3, "character": 0 }));
+        let references: Vec<u64> =
+            responses[3]["result"].as_array().unwrap().iter().map(|l| l["range"]["start"]["line"].as_u64().unwrap()).collect();
+        assert_eq!(references, [1, 3]);
+        assert!(responses[4]["result"]["contents"]["value"].as_str().unwrap().starts_with("add x, y - "));
+        let labels: Vec<&str> = responses[5]["result"].as_array().unwrap().iter().filter_map(|i| i["label"].as_str()).collect();
+        assert_eq!(labels.len(), Command::MNEMONICS.len() + 2);
+        assert!(Command::MNEMONICS.iter().all(|mnemonic| Command::doc(mnemonic).is_some()));
+        assert!(labels.contains(&"double") && labels.contains(&"jnz"));
+        assert_eq!(responses[6]["result"].as_array().unwrap().len(), 2);
+        assert_eq!(responses[7], json!({ "jsonrpc": "2.0", "id": 7, "result": null }));
+    }
+
+    #[test]
+    fn test_lsp_parse_error() {
+        let mut input = b"Content-Length: 3\r\n\r\n{\"i".to_vec();
+        write_message(&mut input, &json!({ "id": 1, "method": "shutdown" })).unwrap();
+        let mut out = Vec::new();
+        serve_lsp(&input[..], &mut out).unwrap();
+        let responses = read_all(&out);
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0]["id"], Value::Null);
+        assert_eq!(responses[0]["error"]["code"], -32700);
+        assert_eq!(responses[1], json!({ "jsonrpc": "2.0", "id": 1, "result": null }));
+    }
+}
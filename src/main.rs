@@ -1,571 +1,515 @@
-use std::{collections::HashMap};
-
-use regex::Regex;
-
-#[derive(Clone, Copy, Debug, PartialEq)]
-struct Register {
-    pub val:i64,
+use std::io::Read;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use simple_assembler_code_wars::{
+    run_batch, serve_dap, serve_lsp, ArithmeticMode, Backtrace, Cpu, CpuState, Debugger, Diagnostic, EmptyReturn, Job, JsonLinesTracer, OutputSink, Program, RunError, StdoutSink, StepOutcome,
+    UninitializedReads, DEFAULT_MAX_CALL_DEPTH,
+};
+
+const USAGE: &str = "usage: simple_assembler_code_wars <command> [options] <file>...
+
+commands:
+  run <file>        run the program, printing its msg output as it executes
+  check <file>      load the program and report its diagnostics
+  trace <file>      run the program, printing each executed instruction
+  debug <file>      debug the program at a gdb-like prompt, `help` lists its commands
+  dap               serve the Debug Adapter Protocol on stdin and stdout, the
+                    program to debug comes with the launch request
+  lsp               serve the Language Server Protocol on stdin and stdout
+  batch <file>...   run every program with every input set, in parallel, printing
+                    one tab-separated line per job: file, inputs, termination,
+                    steps, time and output
+
+options:
+  -r, --reg NAME=VALUE       initial value of a register (repeatable)
+  -n, --max-steps N          stop after N executed instructions
+  -t, --timeout MS           stop after MS milliseconds (ignored by debug)
+  -a, --arithmetic MODE      overflow behaviour: wrapping (default), checked or saturating
+  -f, --fault-handler LABEL  call LABEL on a division by zero or another fault
+  -d, --max-call-depth N     maximum number of pending calls (default 100000, 0 for no limit)
+  --empty-ret ACTION         ret without a call: fault (default) or end
+  -u, --uninitialized MODE   reads of unwritten registers: zero (default), fault or warn
+  --trace-json FILE          run and trace: record every executed instruction as JSON Lines
+  -i, --inputs FILE          batch input sets, one per line as NAME=VALUE ...
+  -j, --jobs N               batch threads (default: one per core)
+  --save-state FILE          write the execution state to FILE when the program stops
+                             without `end` (budget, timeout or fault)
+  --load-state FILE          carry on from a state written by --save-state; -n counts
+                             the steps from the first run
+  -h, --help                 print this help
+
+<file> can be - to read the program from stdin.";
+
+#[derive(Debug, PartialEq)]
+enum Mode {
+    Run,
+    Check,
+    Trace,
+    Batch,
+    Debug,
+    Dap,
+    Lsp,
 }
 
-impl Register {
-    fn set_value(&mut self, new_val:i64 ) -> i64 {
-        self.val=new_val;
-        self.val
-    }
+#[derive(Debug, PartialEq)]
+struct Options {
+    mode: Mode,
+    files: Vec<String>,
+    regs: Vec<(String, i64)>,
+    max_steps: Option<u64>,
+    timeout: Option<Duration>,
+    arithmetic: ArithmeticMode,
+    fault_handler: Option<String>,
+    max_call_depth: Option<usize>,
+    empty_return: EmptyReturn,
+    uninitialized: UninitializedReads,
+    inputs: Option<String>,
+    jobs: usize,
+    trace_json: Option<String>,
+    save_state: Option<String>,
+    load_state: Option<String>,
+}
 
-    fn get_value(&self ) -> i64 {
-        self.val
-    }
+fn parse_reg(reg: &str) -> Result<(String, i64), String> {
+    let (name, val) = reg.split_once('=').ok_or_else(|| format!("expected NAME=VALUE, found `{}`", reg))?;
+    let val = val.parse().map_err(|_| format!("bad value for register {}: `{}`", name, val))?;
+    Ok((name.to_string(), val))
+}
 
-    fn inc(&mut self) {
-        self.val+=1;
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut args = args.iter();
+    let mode = match args.next().map(String::as_str) {
+        Some("run") => Mode::Run,
+        Some("check") => Mode::Check,
+        Some("trace") => Mode::Trace,
+        Some("batch") => Mode::Batch,
+        Some("debug") => Mode::Debug,
+        Some("dap") => Mode::Dap,
+        Some("lsp") => Mode::Lsp,
+        Some(other) => return Err(format!("unknown command `{}`", other)),
+        None => return Err("missing command".to_string()),
+    };
+    let mut files = Vec::new();
+    let mut regs = Vec::new();
+    let mut max_steps = None;
+    let mut timeout = None;
+    let mut arithmetic = ArithmeticMode::default();
+    let mut fault_handler = None;
+    let mut max_call_depth = Some(DEFAULT_MAX_CALL_DEPTH);
+    let mut empty_return = EmptyReturn::default();
+    let mut uninitialized = UninitializedReads::default();
+    let mut inputs = None;
+    let mut jobs = 0;
+    let mut trace_json = None;
+    let mut save_state = None;
+    let mut load_state = None;
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("missing value for {}", name));
+        match arg.as_str() {
+            "-r" | "--reg" => regs.push(parse_reg(value(arg)?)?),
+            "-n" | "--max-steps" => {
+                let n = value(arg)?;
+                max_steps = Some(n.parse().map_err(|_| format!("bad step count `{}`", n))?);
+            }
+            "-t" | "--timeout" => {
+                let ms = value(arg)?;
+                timeout = Some(Duration::from_millis(ms.parse().map_err(|_| format!("bad timeout `{}`", ms))?));
+            }
+            "-a" | "--arithmetic" => {
+                arithmetic = match value(arg)?.as_str() {
+                    "checked" => ArithmeticMode::Checked,
+                    "wrapping" => ArithmeticMode::Wrapping,
+                    "saturating" => ArithmeticMode::Saturating,
+                    other => return Err(format!("unknown arithmetic mode `{}`", other)),
+                };
+            }
+            "-f" | "--fault-handler" => fault_handler = Some(value(arg)?.clone()),
+            "-d" | "--max-call-depth" => {
+                let n = value(arg)?;
+                max_call_depth = match n.parse().map_err(|_| format!("bad call depth `{}`", n))? {
+                    0 => None,
+                    depth => Some(depth),
+                };
+            }
+            "--empty-ret" => {
+                empty_return = match value(arg)?.as_str() {
+                    "fault" => EmptyReturn::Fault,
+                    "end" => EmptyReturn::End,
+                    other => return Err(format!("unknown empty ret action `{}`", other)),
+                };
+            }
+            "-u" | "--uninitialized" => {
+                uninitialized = match value(arg)?.as_str() {
+                    "zero" => UninitializedReads::Zero,
+                    "fault" => UninitializedReads::Fault,
+                    "warn" => UninitializedReads::Warn,
+                    other => return Err(format!("unknown uninitialized mode `{}`", other)),
+                };
+            }
+            "--trace-json" => trace_json = Some(value(arg)?.clone()),
+            "--save-state" => save_state = Some(value(arg)?.clone()),
+            "--load-state" => load_state = Some(value(arg)?.clone()),
+            "-i" | "--inputs" => inputs = Some(value(arg)?.clone()),
+            "-j" | "--jobs" => {
+                let n = value(arg)?;
+                jobs = n.parse().map_err(|_| format!("bad thread count `{}`", n))?;
+            }
+            _ if arg.starts_with('-') && arg != "-" => return Err(format!("unknown option `{}`", arg)),
+            _ if files.is_empty() || mode == Mode::Batch => files.push(arg.clone()),
+            _ => return Err(format!("unexpected argument `{}`", arg)),
+        }
     }
+    if files.is_empty() && mode != Mode::Dap && mode != Mode::Lsp {
+        return Err("missing program file".to_string());
+    }
+    Ok(Options {
+        mode,
+        files,
+        regs,
+        max_steps,
+        timeout,
+        arithmetic,
+        fault_handler,
+        max_call_depth,
+        empty_return,
+        uninitialized,
+        inputs,
+        jobs,
+        trace_json,
+        save_state,
+        load_state,
+    })
+}
 
-    fn dec(&mut self) {
-        self.val-=1;
+fn read_source(file: &str) -> Result<String, String> {
+    let mut source = String::new();
+    if file == "-" {
+        std::io::stdin().read_to_string(&mut source).map_err(|e| format!("stdin: {}", e))?;
+    } else {
+        source = std::fs::read_to_string(file).map_err(|e| format!("{}: {}", file, e))?;
     }
+    Ok(source)
+}
 
-    fn add(&mut self, val:i64) {
-        self.val+=val;
-    }
-    fn sub(&mut self, val:i64) {
-        self.val-=val;
-    }
-    fn mul(&mut self, val:i64) {
-        self.val*=val;
-    }
-    fn div(&mut self, val:i64) {
-        self.val/=val;
+fn print_diagnostics(file: &str, diagnostics: &[Diagnostic]) {
+    for d in diagnostics {
+        let pos = d.error.pos();
+        let severity = if d.is_error() { "error" } else { "warning" };
+        eprintln!("{}:{}:{}: {}: {}", file, pos.line, pos.column, severity, d.error.message());
+        eprintln!("    {}", pos.snippet);
     }
 }
 
-#[derive(PartialEq,Debug,Clone, Copy)]
-enum Param {
-    Val(i64),
-    Register(char),  
-  } 
-
-#[derive(PartialEq,Debug, Clone)]
-  enum Command {
-    Move(char, Param),
-    Inc(char),
-    Dec(char),
-    Jnz(Param,Param),
-    Add(char,Param),    // add x, y - add the content of the register x with y (either an integer or the value of a register) and stores the result in x (i.e. register[x] += y).
-    Sub(char, Param),   // sub x, y - subtract y (either an integer or the value of a register) from the register x and stores the result in x (i.e. register[x] -= y).
-    Mul(char, Param),   // mul x, y - same with multiply (i.e. register[x] *= y).
-    Div(char, Param),   // div x, y - same with integer division (i.e. register[x] /= y).
-    Label(String),      // label: - define a label position (label = identifier + ":", an identifier being a string that does not match any other command). Jump commands and call are aimed to these labels positions in the program.
-    Jmp(String),        // jmp lbl - jumps to the label lbl.
-    Cmp(Param,Param),   // cmp x, y - compares x (either an integer or the value of a register) and y (either an integer or the value of a register). The result is used in the conditional jumps (jne, je, jge, jg, jle and jl)
-    Jne(String),        // jne lbl - jump to the label lbl if the values of the previous cmp command were not equal.
-    Je(String),         // je lbl - jump to the label lbl if the values of the previous cmp command were equal.
-    Jge(String),        // jge lbl - jump to the label lbl if x was greater or equal than y in the previous cmp command.
-    Jg(String),         // jg lbl - jump to the label lbl if x was greater than y in the previous cmp command.
-    Jle(String),        // jle lbl - jump to the label lbl if x was less or equal than y in the previous cmp command.
-    Jl(String),         // jl lbl - jump to the label lbl if x was less than y in the previous cmp command.
-    Call(String),       // call lbl - call to the subroutine identified by lbl. When a ret is found in a subroutine, the instruction pointer should return to the instruction next to this call command.
-    Ret,                // ret - when a ret is found in a subroutine, the instruction pointer should return to the instruction that called the current function.
-    Msg(String),   // msg 'Register: ', x - this instruction stores the output of the program. It may contain text strings (delimited by single quotes) and registers. The number of arguments isn't limited and will vary, depending on the program.
-    End,                // end - this instruction indicates that the program ends correctly, so the stored output is returned (if the program terminates without this instruction it should return the default output: see below).
-    Comment,            // ; comment - comments should not be taken in consideration during the execution of the program.
+/// Streams the output to stdout, remembering whether it wrote anything.
+#[derive(Default)]
+struct Streamed {
+    written: bool,
 }
 
-#[derive(Debug, PartialEq, Clone)]
-enum Comparison {
-    Equal,
-    Less,
-    Greater
+impl OutputSink for Streamed {
+    fn write_msg(&mut self, text: &str) {
+        self.written |= !text.is_empty();
+        StdoutSink.write_msg(text);
+    }
 }
 
-#[derive(Clone)]
-struct Cpu{
-    regs:HashMap<char, Register>,
-    code:Vec<Command>,
-    compare:Option<Comparison>,
-    labels:HashMap<String,usize>,
-    sub_calls:Vec<usize>,
+/// Runs the loaded program from its current instruction until `end`.
+fn finish(cpu: &mut Cpu) -> Result<String, RunError> {
+    match cpu.resume()? {
+        StepOutcome::End => Ok(cpu.output().to_string()),
+        _ => Err(RunError::NoEnd),
+    }
 }
 
-impl Cpu {
-    fn new() -> Self {
-
-        let registers:HashMap<char, Register>=HashMap::new();
-        Cpu { regs: registers,
-             code: Vec::new(),
-             compare:None,
-             labels:HashMap::new(),
-            sub_calls: Vec::new() }
+/// Runs the loaded program from its current instruction, printing every
+/// instruction.
+fn trace(cpu: &mut Cpu) -> Result<String, RunError> {
+    loop {
+        let ip = cpu.ip();
+        let outcome = cpu.step()?;
+        if outcome != StepOutcome::NoEnd {
+            let regs: Vec<String> = cpu.registers().map(|(name, val)| format!("{}={}", name, val)).collect();
+            println!("{:>5}  {:<30} {}", ip, cpu.code()[ip].to_string(), regs.join(" "));
+        }
+        match outcome {
+            StepOutcome::End => return Ok(cpu.output().to_string()),
+            StepOutcome::NoEnd => return Err(RunError::NoEnd),
+            _ => {}
+        }
     }
+}
 
-    fn load_code_from_vec(&mut self, code:&[&str]) -> Result<(), String>{
-
-        self.load_code(code.join("\n").as_str())
-
+/// Loads a program with the loading options, printing its diagnostics.
+fn load(file: &str, options: &Options) -> Option<Arc<Program>> {
+    let source = match read_source(file) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return None;
+        }
+    };
+    let mut cpu = Cpu::new();
+    cpu.set_uninitialized_reads(options.uninitialized);
+    match cpu.load_code(&source) {
+        Ok(warnings) => print_diagnostics(file, &warnings),
+        Err(diagnostics) => {
+            print_diagnostics(file, &diagnostics);
+            return None;
+        }
     }
+    Some(cpu.program().clone())
+}
 
-    fn load_code(&mut self, txt: &str) -> Result<(), String> {
-        //println!("Input to load_code:\n{}", txt); // Debug output
+/// Applies the run options to a cpu, which fails if the fault handler
+/// isn't in its program.
+fn configure(cpu: &mut Cpu, options: &Options) -> Result<(), String> {
+    cpu.set_max_steps(options.max_steps);
+    cpu.set_arithmetic_mode(options.arithmetic);
+    cpu.set_max_call_depth(options.max_call_depth);
+    cpu.set_empty_return(options.empty_return);
+    cpu.set_uninitialized_reads(options.uninitialized);
+    // the time spent at the debugger prompt must not count
+    if options.mode != Mode::Debug {
+        cpu.set_deadline(options.timeout.map(|timeout| Instant::now() + timeout));
+    }
+    cpu.set_fault_handler(options.fault_handler.as_deref())
+}
 
-        let reg = |x:&str| x.chars().next().unwrap();
-        
-        let param = |y:&str|{
-            if let Ok(val)=y.parse::<i64>() {
-                return Param::Val(val);
-            } else if y.chars().next().unwrap().is_alphabetic() {
-                let reg=y.chars().next().unwrap();
-                return Param::Register(reg);
-            } else {
-                panic!("Bad param {}", y);
+/// Input sets of a batch: the lines of the inputs file, each extended with
+/// the `-r` registers, or just the `-r` registers.
+fn input_sets(options: &Options) -> Result<Vec<Vec<(String, i64)>>, String> {
+    let Some(file) = &options.inputs else {
+        return Ok(vec![options.regs.clone()]);
+    };
+    let text = std::fs::read_to_string(file).map_err(|e| format!("{}: {}", file, e))?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let mut regs = options.regs.clone();
+            for reg in line.split_whitespace() {
+                regs.push(parse_reg(reg).map_err(|e| format!("{}:{}: {}", file, i + 1, e))?);
             }
-        };
+            Ok(regs)
+        })
+        .collect()
+}
 
-        let param_x = |x:&str|{
-            if let Ok(val)=x.parse::<i64>() {
-                return Param::Val(val);
-            } else if x.chars().next().unwrap().is_alphabetic() {
-                let reg=x.chars().next().unwrap();
-                return Param::Register(reg);
-            } else {
-                panic!("Bad param_x {}", x);
-            }
+fn batch(options: &Options) -> ExitCode {
+    let sets = match input_sets(options) {
+        Ok(sets) => sets,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+    let mut jobs = Vec::new();
+    let mut files = Vec::new();
+    for file in &options.files {
+        let Some(program) = load(file, options) else {
+            return ExitCode::from(2);
         };
+        for inputs in &sets {
+            jobs.push(Job { program: program.clone(), inputs: inputs.clone() });
+            files.push(file);
+        }
+    }
 
-        let re_trim=Regex::new(r"\s+").unwrap();
-        let mut address=0;
-        for (i, line) in txt.lines().enumerate() {
-            let line=re_trim.replace_all(line, " ");
-            let line: &str = line.split(';').next().unwrap_or("");
-
-            let line = line.trim();
-            //println!("Processing line {}:'{}'  -- adress:{}", i, line, address); // Debug output
-            if line.starts_with("msg") {
-                self.code.push(Command::Msg(line.chars().skip(4).map(|c| c).collect::<String>()));
-                address+=1;
-                continue;
+    let results = run_batch(&jobs, options.jobs, |cpu| configure(cpu, options));
+    let mut failed = false;
+    for ((file, job), result) in files.iter().zip(&jobs).zip(&results) {
+        let inputs: Vec<String> = job.inputs.iter().map(|(name, val)| format!("{}={}", name, val)).collect();
+        let inputs = if inputs.is_empty() { "-".to_string() } else { inputs.join(" ") };
+        match &result.report {
+            Ok(report) => {
+                failed |= !report.succeeded();
+                println!(
+                    "{}\t{}\t{}\t{}\t{:.3?}\t{:?}",
+                    file,
+                    inputs,
+                    report.termination.to_string().replace('\n', " "),
+                    report.steps,
+                    result.elapsed,
+                    report.output
+                );
             }
-            if line.starts_with(";") {continue;}
-            if line.len()==0{continue;}
-            let parts:Vec<&str>=line.split_whitespace().collect();
-            //println!("Parts:\t{:?}", parts);
-
-            match parts.as_slice() {
-                ["inc", x] => {
-                    self.code.push(Command::Inc(reg(x)));    
-                },
-                ["dec", x] => {
-                    self.code.push(Command::Dec(reg(x)));    
-                },
-                ["mov", x, y] => {
-                    self.code.push(Command::Move(reg(x), param(y)));
-                },
-                ["jnz", x, y] =>{
-                    self.code.push(Command::Jnz(param(x), param(y)));
-                },
-                ["add", x, y] => {
-                    self.code.push(Command::Add(reg(x), param(y)))
-                },
-                ["sub", x, y] => {
-                    self.code.push(Command::Sub(reg(x), param(y)))
-                },
-                ["mul", x, y] => {
-                    self.code.push(Command::Mul(reg(x), param(y)))
-                },
-                ["div", x, y] => {
-                    self.code.push(Command::Div(reg(x), param(y)))
-                },
-                ["cmp", x, y] => {
-                    self.code.push(Command::Cmp(param_x(x), param(y)))
-                },
-                ["jmp", x] => {
-                    self.code.push(Command::Jmp(x.to_string()));
-                },
-                [label] if label.ends_with(':')=> {
-                    let label_name = label.trim_end_matches(':');
-                    self.labels.insert(label_name.to_string(), address);
-                    self.code.push(Command::Label(label_name.to_string()));
-                },
-                ["jne", x] => {
-                    self.code.push(Command::Jne(x.to_string()));
-                },
-                ["je", x] => {
-                    self.code.push(Command::Je(x.to_string()));
-                },
-                ["jge", x] => {
-                    self.code.push(Command::Jge(x.to_string()));
-                },
-                ["jg", x] => {
-                    self.code.push(Command::Jg(x.to_string()));
-                },
-                ["jle", x] => {
-                    self.code.push(Command::Jle(x.to_string()));
-                },
-                ["jl", x] => {
-                    self.code.push(Command::Jl(x.to_string()));
-                },
-                ["call", x] => {
-                    self.code.push(Command::Call(x.to_string()));
-                },
-                ["ret"] => {
-                    self.code.push(Command::Ret);
-                },
-                ["end"] => {
-                    self.code.push(Command::End);
-                },
-                [";"] => {
-                    self.code.push(Command::Comment);
-                },
-                _ => panic!("Unknown instruction {}", line),
+            Err(e) => {
+                failed = true;
+                println!("{}\t{}\t{}\t0\t{:.3?}\t\"\"", file, inputs, e, result.elapsed);
             }
-            address+=1;
         }
-        Ok(())
     }
-
-    fn print_status(&self, address: &usize, code:&Command){
-        println!("Address : {}\t{:?}", address,code);
-        for (c,reg) in &   self.regs {
-            println!("{} : {}", c, reg.val);
-        }
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
     }
+}
 
-    fn run (&mut self) -> Result<String,String>{
-        let mut address=0;
-        let mut out:String=String::new();
-
-        while address < self.code.len(){
-            let code= self.code[address].clone();
-            //self.print_status(&address, &code);
-            match code {
-
-                Command::Dec(a) => {
-                    self.parse_register(&a)?.dec();
-                },
-                Command::Inc(a) => {
-                    self.parse_register(&a)?.inc();
-                },
-                Command::Move(a, b) => {
-                    self.set_register_value(a,self.get_param_value(&b)?);
-                },
-                Command::Jnz(a, b) => {
-                    let condition=self.get_param_value(&a)? as usize;
-                    let jump = self.get_param_value(&b)?;
-                    if condition !=0 {
-                        if jump < 0 && address< jump.abs() as usize {
-                            return Err(format!("Bad jump from {} -> {}", address, jump));
-                        }
-                        address = address.wrapping_add(jump as usize);
-                        continue;
-                    }
-                },
-                Command::Add(r, p) => {
-                    let val = self.get_param_value(&p)?;
-                    self.parse_register(&r)?.add(val);
-                },
-                Command::Sub(r, p) => {
-                    let val = self.get_param_value(&p)?;
-                    self.parse_register(&r)?.sub(val);
-                },
-                Command::Mul(r, p) => {
-                    let val = self.get_param_value(&p)?;
-                    self.parse_register(&r)?.mul(val);
-                },
-                Command::Div(r, p) => {
-                    let val = self.get_param_value(&p)?;
-                    self.parse_register(&r)?.div(val);
-                },
-                Command::Cmp(p1, p2) => {
-                    let val_1 = self.get_param_value(&p1)?;
-                    let val_2 = self.get_param_value(&p2)?;
-                    if val_1==val_2 {self.compare=Some(Comparison::Equal);}
-                    else if val_1<val_2 { self.compare=Some(Comparison::Less);}
-                    else { self.compare=Some(Comparison::Greater);}
-                },
-                Command::Label(x) => {},
-                Command::Jmp(x) => {
-                    address=self.get_label_address(&x); continue;
-                },
-                Command::Jne(x) => {
-                    if self.compare!=None && self.compare!=Some(Comparison::Equal) {
-                        address=self.get_label_address(&x); continue;
-                    }
-                },
-                Command::Je(x) => {
-                    if self.compare==Some(Comparison::Equal) {
-                        address=self.get_label_address(&x); continue;
-                    }
-                },
-                Command::Jge(x) => {
-                    if self.compare==Some(Comparison::Equal) || self.compare==Some(Comparison::Greater) {
-                        address=self.get_label_address(&x); continue;
-                    }
-                },
-                Command::Jg(x) => {
-                    if self.compare==Some(Comparison::Greater) {
-                        address=self.get_label_address(&x); continue;
-                    }
-                },
-                Command::Jle(x) => {
-                    if self.compare==Some(Comparison::Equal) || self.compare==Some(Comparison::Less) {
-                        address=self.get_label_address(&x); continue;
-                    }
-                },
-                Command::Jl(x) => {
-                    if self.compare==Some(Comparison::Less) {
-                        address=self.get_label_address(&x); continue;
-                    }
-                },
-                Command::Call(x) => {
-                    self.sub_calls.push(address);
-                    address=self.get_label_address(&x.to_string());
-                    continue;
-                },
-                Command::Ret => {
-                    address=self.sub_calls.pop().unwrap();
-                },
-                Command::Msg(x) => {
-                    let mut s = String::new();
-                    let mut in_text=false;
-                    for c in x.chars() {
-                        match c {
-                            '\'' => {
-                                in_text= !in_text;
-                            },
-                            _ if in_text => s.push(c),
-                            'a'..='z' if in_text==false => {
-                                let o = format!("{}", self.get_register_value(&c).unwrap_or(0));
-                                s.push_str(o.as_str());
-                            },
-                            ';' if !in_text => {break;}
-                            _ => {
-                            },
-                            
-                        }
-                    }
-                    out.push_str(s.as_str());
-                },
-                Command::End => {return Ok(out)},
-                _ => panic!(),
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match options.mode {
+        Mode::Batch => return batch(&options),
+        Mode::Dap | Mode::Lsp => {
+            let (input, output) = (std::io::stdin().lock(), std::io::stdout().lock());
+            let served = match options.mode {
+                Mode::Dap => serve_dap(input, output),
+                _ => serve_lsp(input, output),
+            };
+            return match served {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    ExitCode::from(2)
+                }
             }
-            address+=1;
         }
-        Err("no end".to_string())
-    }
-
-    fn get_register_value(&mut self, r:&char) -> Result<i64, String>{
-        
-        if let Ok(reg)=self.parse_register(r) {
-            return Ok(reg.get_value());
+        _ => {}
+    }
+
+    let file = &options.files[0];
+    let Some(program) = load(file, &options) else {
+        return ExitCode::from(2);
+    };
+    if options.mode == Mode::Check {
+        return ExitCode::SUCCESS;
+    }
+    let mut cpu = program.cpu();
+    if let Some(file) = &options.load_state {
+        let state = std::fs::read_to_string(file)
+            .map_err(|e| e.to_string())
+            .and_then(|text| serde_json::from_str(&text).map_err(|e| e.to_string()))
+            .and_then(|json| CpuState::from_json(&json))
+            .and_then(|state| cpu.load_state(&state));
+        if let Err(e) = state {
+            eprintln!("error: {}: {}", file, e);
+            return ExitCode::from(2);
         }
-        Err(format!("Get register {} value error", r))
     }
-
-    fn get_param_value(&self, p:&Param) -> Result<i64, String>{
-        match p {
-            Param::Register(r) => { Ok(self.regs.get(&r).unwrap().val) },
-            Param::Val(v) => Ok(*v),
+    for (name, val) in &options.regs {
+        if let Err(e) = cpu.set_register_value(name, *val) {
+            eprintln!("error: {}", e);
+            return ExitCode::from(2);
         }
     }
-    
-    fn set_register_value(&mut self,r:char, val:i64) {
-        let mut reg=self.regs.entry(r).or_insert(Register{val:0});
-        reg.set_value(val);
-        
-    }
 
-    fn parse_register(&mut self, input: &char) -> Result<&mut Register, String> {
-        if input.is_alphabetic()  {
-//            let reg_name= input.chars().next().unwrap();
-            let reg= self.regs.entry(*input).or_insert_with(|| Register{val:0});
-            return Ok(reg);
-        } else {
-            return Err(format!("Unknown register {}", input));
+    if let Err(e) = configure(&mut cpu, &options) {
+        eprintln!("error: {}", e);
+        return ExitCode::from(2);
+    }
+    if let Some(file) = &options.trace_json {
+        match std::fs::File::create(file) {
+            Ok(f) => cpu.set_tracer(Some(Arc::new(Mutex::new(JsonLinesTracer::new(std::io::BufWriter::new(f)))))),
+            Err(e) => {
+                eprintln!("error: {}: {}", file, e);
+                return ExitCode::from(2);
+            }
         }
     }
-
-    fn get_label_address (&self, label:&String) -> usize {
-        *self.labels.get(label).unwrap()
+    if options.mode == Mode::Debug {
+        return match Debugger::new(cpu).run(std::io::stdin().lock(), std::io::stdout()) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                ExitCode::from(2)
+            }
+        };
     }
-
-}
-
-pub struct AssemblerInterpreter {
-}
-
-impl AssemblerInterpreter {
-    pub fn interpret(input: &str) -> Option<String> {
-        let mut cpu = Cpu::new();
-        cpu.load_code(input);
-        let res = cpu.run();
-        if res.is_ok(){
-            return Some(res.unwrap());
+    // a saved state holds the output, so it is buffered
+    let streamed = options.mode == Mode::Run && options.save_state.is_none() && options.load_state.is_none();
+    let stream = Arc::new(Mutex::new(Streamed::default()));
+    if streamed {
+        cpu.set_output_sink(Some(stream.clone()));
+    }
+    let result = match options.mode {
+        Mode::Trace => trace(&mut cpu),
+        _ => finish(&mut cpu),
+    };
+    match result {
+        Ok(out) => {
+            println!("{}", out);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            // a streamed output has been shown already, it only needs its newline
+            if streamed {
+                if stream.lock().unwrap_or_else(|e| e.into_inner()).written {
+                    println!();
+                }
+            } else if options.mode == Mode::Run || matches!(e, RunError::Cancelled { .. } | RunError::TimedOut { .. }) {
+                println!("{}", cpu.output());
+            }
+            eprintln!("error: {}", e);
+            let frames = cpu.backtrace();
+            if !matches!(e, RunError::Fault(_)) && !frames.is_empty() {
+                eprintln!("{}", Backtrace(&frames));
+            }
+            if let Some(file) = &options.save_state {
+                let state = serde_json::to_string_pretty(&cpu.save_state().to_json()).unwrap_or_default();
+                match std::fs::write(file, state + "\n") {
+                    Ok(()) => eprintln!("state saved to {}", file),
+                    Err(e) => eprintln!("error: {}: {}", file, e),
+                }
+            }
+            ExitCode::FAILURE
         }
-        None
     }
 }
 
 #[cfg(test)]
 mod tests {
-
-    use crate::Param;
     use super::*;
 
-    use super::Register;
-    use super::Cpu;
-    use super::Command;
-
-    fn init_cpu() -> Cpu {
-        Cpu::new()
-    }
-/*
-    #[test]
-    fn test_register_set_value() {
-        let mut register = Register { val: 0 };
-        register.set_value(42);
-        assert_eq!(register.val, 42);
-    }
-
-    #[test]
-    fn test_register_get_value() {
-        let register = Register { val: 42 };
-        assert_eq!(register.get_value(), 42);
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
     }
 
     #[test]
-    fn test_register_inc() {
-        let mut register = Register { val: 0 };
-        register.inc();
-        assert_eq!(register.val, 1);
-    }
-
-    #[test]
-    fn test_register_dec() {
-        let mut register = Register { val: 42 };
-        register.dec();
-        assert_eq!(register.val, 41);
-    }
-    
-    #[test]
-    fn test_register_mul() {
-        let mut register = Register { val: 42 };
-        register.mul(2);
-        assert_eq!(register.val, 84);
+    fn test_parse_args() {
+        let options = parse_args(&args("trace prog.asm --reg a=5 -r count=-1 -n 100 -t 250 -a checked -f oops -d 0 --empty-ret end -u warn")).unwrap();
+        assert_eq!(options, Options {
+            mode: Mode::Trace,
+            files: vec!["prog.asm".to_string()],
+            regs: vec![("a".to_string(), 5), ("count".to_string(), -1)],
+            max_steps: Some(100),
+            timeout: Some(Duration::from_millis(250)),
+            arithmetic: ArithmeticMode::Checked,
+            fault_handler: Some("oops".to_string()),
+            max_call_depth: None,
+            empty_return: EmptyReturn::End,
+            uninitialized: UninitializedReads::Warn,
+            inputs: None,
+            jobs: 0,
+            trace_json: None,
+            save_state: None,
+            load_state: None,
+        });
+        assert!(parse_args(&args("run")).is_err());
+        assert!(parse_args(&args("run a.asm -r a")).is_err());
+        assert!(parse_args(&args("exec a.asm")).is_err());
+        assert_eq!(parse_args(&args("check -")).unwrap().files, ["-"]);
+        assert!(parse_args(&args("run a.asm b.asm")).is_err());
+        assert!(parse_args(&args("dap")).unwrap().files.is_empty());
+        assert_eq!(parse_args(&args("lsp")).unwrap().mode, Mode::Lsp);
+
+        let options = parse_args(&args("batch a.asm b.asm -i inputs.txt -j 8")).unwrap();
+        assert_eq!(options.files, ["a.asm", "b.asm"]);
+        assert_eq!((options.inputs.as_deref(), options.jobs), (Some("inputs.txt"), 8));
+
+        let options = parse_args(&args("run a.asm --load-state in.json --save-state out.json")).unwrap();
+        assert_eq!((options.load_state.as_deref(), options.save_state.as_deref()), (Some("in.json"), Some("out.json")));
     }
-    #[test]
-    fn test_register_div() {
-        let mut register = Register { val: 42 };
-        register.div(2);
-        assert_eq!(register.val, 21);
-    }
-    #[test]
-    fn test_register_add() {
-        let mut register = Register { val: 42 };
-        register.add(2);
-        assert_eq!(register.val, 44);
-    }
-    #[test]
-    fn test_register_sub() {
-        let mut register = Register { val: 42 };
-        register.sub(2);
-        assert_eq!(register.val, 40);
-    }
-    
-    #[test]
-    fn test_cpu_load_code_inc() {
-        let mut cpu = init_cpu();
-        cpu.load_code("inc a");
-        let reg=cpu.parse_register(&'a').unwrap();
-        assert_eq!(cpu.code[0], Command::Inc('a'));
-    }
-
-    
-    #[test]
-    fn test_cpu_load_code_from_vec() {
-        let mut cpu = init_cpu();
-        let code = ["mov a 5", "inc a", "dec a", "dec a", "jnz a -1", "inc a"];
-        cpu.load_code_from_vec(&code);
-        cpu.run();
-        assert_eq!(cpu.get_register_value(&'a'), Ok(1));
-    }
-    #[test]
-    fn test_cpu_operations() {
-        let mut cpu = init_cpu();
-        let code = ["mov a 5", "sub a 2", "add a 3", "mul a 2", "mov b a", "div a b"];
-        cpu.load_code_from_vec(&code);
-        cpu.run();
-        assert_eq!(cpu.get_register_value(&'a'), Ok(1));
-    }
-
-    #[test]
-    fn test_labels() {
-        let mut cpu = init_cpu();
-        let code = ["coucou:", "mov a 3", "first:", "second:"];
-        let _ = cpu.load_code_from_vec(&code);
-        let _ = cpu.run();
-        assert_eq!(*cpu.labels.get(&"second".to_string()).unwrap(), 3 as usize);
-    }
-
-    #[test]
-    fn test_jump() {
-        let mut cpu = init_cpu();
-        let code = ["mov a 3", "coucou:", "dec a", "cmp a 0", "jne coucou", "end"];
-        let _ = cpu.load_code_from_vec(&code);
-        let _ = cpu.run();
-        assert_eq!(cpu.regs.get(&'a').unwrap().val, 0);
-    }
-    
-
-    #[test]
-    fn test_sub() {
-        let mut cpu = init_cpu();
-        let code = ["mov a 3", "coucou:","call sub_dec", "cmp a 0", "jne coucou", "end", "sub_dec:", "dec a", "ret"];
-        let _ = cpu.load_code_from_vec(&code);
-        let _ = cpu.run();
-        assert_eq!(cpu.regs.get(&'a').unwrap().val, 0);
-    }
-
-    #[test]
-    fn test_msg() {
-        let mut cpu = init_cpu();
-        let code = ["mov a 3", "mov b 2", "mov c 6", "msg   'mul(', a, ', ', b, ') = ', c        "];
-        let _ = cpu.load_code_from_vec(&code);
-        let out = cpu.run().unwrap();
-        assert_eq!(out, "mul(3, 2) = 6");
-    }
-    
-    #[test]
-    fn test_cw1() {
-        let mut cpu = init_cpu();
-        let code = "\n; My first program\nmov  a, 5\ninc  a\ncall function\nmsg  '(5+1)/2 = ', a    ; output message\nend\n\nfunction:\n    div  a, 2\n    ret\n";
-        let _ = cpu.load_code(&code);
-        let out = cpu.run().unwrap();
-        assert_eq!(out, "(5+1)/2 = 3");
-    }
- */
-
-    #[test]
-    fn simple_test() {
-        let simple_programs = &[
-            "\n; My first program\nmov  a, 5\ninc  a\ncall function\nmsg  '(5+1)/2 = ', a    ; output message\nend\n\nfunction:\n    div  a, 2\n    ret\n",
-            "\nmov   a, 5\nmov   b, a\nmov   c, a\ncall  proc_fact\ncall  print\nend\n\nproc_fact:\n    dec   b\n    mul   c, b\n    cmp   b, 1\n    jne   proc_fact\n    ret\n\nprint:\n    msg   a, '! = ', c ; output text\n    ret\n",
-            "\nmov   a, 8            ; value\nmov   b, 0            ; next\nmov   c, 0            ; counter\nmov   d, 0            ; first\nmov   e, 1            ; second\ncall  proc_fib\ncall  print\nend\n\nproc_fib:\n    cmp   c, 2\n    jl    func_0\n    mov   b, d\n    add   b, e\n    mov   d, e\n    mov   e, b\n    inc   c\n    cmp   c, a\n    jle   proc_fib\n    ret\n\nfunc_0:\n    mov   b, c\n    inc   c\n    jmp   proc_fib\n\nprint:\n    msg   'Term ', a, ' of Fibonacci series is: ', b        ; output text\n    ret\n",
-            "\nmov   a, 11           ; value1\nmov   b, 3            ; value2\ncall  mod_func\nmsg   'mod(', a, ', ', b, ') = ', d        ; output\nend\n\n; Mod function\nmod_func:\n    mov   c, a        ; temp1\n    div   c, b\n    mul   c, b\n    mov   d, a        ; temp2\n    sub   d, c\n    ret\n",
-            "\nmov   a, 81         ; value1\nmov   b, 153        ; value2\ncall  init\ncall  proc_gcd\ncall  print\nend\n\nproc_gcd:\n    cmp   c, d\n    jne   loop\n    ret\n\nloop:\n    cmp   c, d\n    jg    a_bigger\n    jmp   b_bigger\n\na_bigger:\n    sub   c, d\n    jmp   proc_gcd\n\nb_bigger:\n    sub   d, c\n    jmp   proc_gcd\n\ninit:\n    cmp   a, 0\n    jl    a_abs\n    cmp   b, 0\n    jl    b_abs\n    mov   c, a            ; temp1\n    mov   d, b            ; temp2\n    ret\n\na_abs:\n    mul   a, -1\n    jmp   init\n\nb_abs:\n    mul   b, -1\n    jmp   init\n\nprint:\n    msg   'gcd(', a, ', ', b, ') = ', c\n    ret\n",
-            "\ncall  func1\ncall  print\nend\n\nfunc1:\n    call  func2\n    ret\n\nfunc2:\n    ret\n\nprint:\n    msg 'This program should return null'\n",
-            "\nmov   a, 2            ; value1\nmov   b, 10           ; value2\nmov   c, a            ; temp1\nmov   d, b            ; temp2\ncall  proc_func\ncall  print\nend\n\nproc_func:\n    cmp   d, 1\n    je    continue\n    mul   c, a\n    dec   d\n    call  proc_func\n\ncontinue:\n    ret\n\nprint:\n    msg a, '^', b, ' = ', c\n    ret\n"];
-
-        let expected = &[
-            Some(String::from("(5+1)/2 = 3")),
-            Some(String::from("5! = 120")),
-            Some(String::from("Term 8 of Fibonacci series is: 21")),
-            Some(String::from("mod(11, 3) = 2")),
-            Some(String::from("gcd(81, 153) = 9")),
-            None,
-            Some(String::from("2^10 = 1024"))];
-
-        for (prg, exp) in simple_programs.iter().zip(expected) {
-            let actual = AssemblerInterpreter::interpret(*prg);
-            assert_eq!(actual, *exp);
-        }
-    }
-}
\ No newline at end of file
+}
@@ -0,0 +1,73 @@
+use std::io::Write;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+/// Receives the text of each `msg` as it executes, see
+/// `Cpu::set_output_sink`.
+pub trait OutputSink {
+    fn write_msg(&mut self, text: &str);
+}
+
+/// A sink shared between the cpu and its owner, who keeps a clone to read
+/// what was written.
+pub type SharedSink = Arc<Mutex<dyn OutputSink + Send>>;
+
+/// Buffers the output, the default behaviour of `Cpu`.
+impl OutputSink for String {
+    fn write_msg(&mut self, text: &str) {
+        self.push_str(text);
+    }
+}
+
+/// One entry per executed msg.
+impl OutputSink for Vec<String> {
+    fn write_msg(&mut self, text: &str) {
+        self.push(text.to_string());
+    }
+}
+
+/// Sends one message per executed msg. A closed channel drops the output.
+impl OutputSink for Sender<String> {
+    fn write_msg(&mut self, text: &str) {
+        let _ = self.send(text.to_string());
+    }
+}
+
+/// Writes the output to stdout as it is produced.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdoutSink;
+
+impl OutputSink for StdoutSink {
+    fn write_msg(&mut self, text: &str) {
+        let mut stdout = std::io::stdout().lock();
+        let _ = stdout.write_all(text.as_bytes()).and_then(|_| stdout.flush());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cpu, RunError};
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_output_sinks() {
+        let program = "mov a, 0\nloop:\ninc a\nmsg 'a = ', a\ncmp a, 3\njne loop";
+        let mut cpu = Cpu::new();
+        cpu.load_code(program).unwrap();
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        cpu.set_output_sink(Some(lines.clone()));
+        assert_eq!(cpu.run(), Err(RunError::NoEnd));
+        assert_eq!(*lines.lock().unwrap(), vec!["a = 1", "a = 2", "a = 3"]);
+        assert_eq!(cpu.output(), "");
+
+        let (tx, rx) = mpsc::channel();
+        cpu.set_output_sink(Some(Arc::new(Mutex::new(tx))));
+        assert_eq!(cpu.run(), Err(RunError::NoEnd));
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec!["a = 1", "a = 2", "a = 3"]);
+
+        cpu.set_output_sink(None);
+        assert_eq!(cpu.run(), Err(RunError::NoEnd));
+        assert_eq!(cpu.output(), "a = 1a = 2a = 3");
+    }
+}
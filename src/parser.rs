@@ -0,0 +1,189 @@
+use crate::error::{AsmError, SourcePos};
+use crate::lexer::{tokenize, Span, Token, TokenKind};
+use crate::{Command, MsgArg, Param, Target};
+
+/// A parsed source line: the command, the span of its mnemonic (or label
+/// name) and the spans of its operands.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub command: Command,
+    pub span: Span,
+    pub operands: Vec<Span>,
+}
+
+/// Parses one source line. Blank and comment-only lines give `None`.
+/// When `declared` is set, registers must be one of these names.
+///
+/// ```text
+/// line     := [ instr | label ] [ comment ]
+/// label    := ident ':'
+/// instr    := ident [ operand { ',' operand } ]
+/// operand  := ident | int | string
+/// ```
+pub fn parse_line(line_no: usize, raw: &str, declared: Option<&[String]>) -> Result<Option<Statement>, AsmError> {
+    let tokens = tokenize(line_no, raw)?;
+    let tokens: Vec<&Token> = tokens.iter().filter(|t| !matches!(t.kind, TokenKind::Comment(_))).collect();
+    let unexpected = |found: Option<&Token>, expected: &'static str| match found {
+        Some(t) => AsmError::UnexpectedToken { pos: SourcePos::new(t.span, raw), found: t.kind.describe(), expected },
+        None => AsmError::UnexpectedToken { pos: SourcePos::new(end_of(line_no, raw), raw), found: "end of line".to_string(), expected },
+    };
+
+    let (mnemonic, span, rest) = match tokens.as_slice() {
+        [] => return Ok(None),
+        [Token { kind: TokenKind::Ident(name), span }, Token { kind: TokenKind::Colon, .. }, rest @ ..] => {
+            if let Some(t) = rest.first() {
+                return Err(unexpected(Some(t), "end of line after a label"));
+            }
+            return Ok(Some(Statement { command: Command::Label(name.clone()), span: *span, operands: Vec::new() }));
+        }
+        [Token { kind: TokenKind::Ident(name), span }, rest @ ..] => (name.as_str(), *span, rest),
+        [t, ..] => return Err(unexpected(Some(t), "an instruction or a label")),
+    };
+
+    let mut operands: Vec<&Token> = Vec::new();
+    let mut iter = rest.iter();
+    while let Some(t) = iter.next() {
+        match t.kind {
+            TokenKind::Ident(_) | TokenKind::Int(_) | TokenKind::Str(_) => operands.push(t),
+            _ => return Err(unexpected(Some(t), "an operand")),
+        }
+        match iter.next() {
+            None => break,
+            Some(Token { kind: TokenKind::Comma, .. }) if iter.len() == 0 => return Err(unexpected(None, "an operand")),
+            Some(Token { kind: TokenKind::Comma, .. }) => {}
+            Some(t) => return Err(unexpected(Some(t), "`,`")),
+        }
+    }
+
+    let expected = match mnemonic {
+        "msg" => None,
+        "ret" | "end" => Some(0),
+        "inc" | "dec" | "jmp" | "jne" | "je" | "jge" | "jg" | "jle" | "jl" | "call" => Some(1),
+        "mov" | "jnz" | "add" | "sub" | "mul" | "div" | "cmp" => Some(2),
+        _ => {
+            return Err(AsmError::UnknownInstruction { pos: SourcePos::new(span, raw), mnemonic: mnemonic.to_string() })
+        }
+    };
+    if let Some(expected) = expected.filter(|n| *n != operands.len()) {
+        return Err(AsmError::OperandCount {
+            pos: SourcePos::new(span, raw),
+            mnemonic: mnemonic.to_string(),
+            expected,
+            found: operands.len(),
+        });
+    }
+
+    let bad = |t: &Token, expected: &'static str| AsmError::BadOperand {
+        pos: SourcePos::new(t.span, raw),
+        operand: t.kind.describe(),
+        expected,
+    };
+    let reg = |i: usize| {
+        let t = operands[i];
+        match &t.kind {
+            TokenKind::Ident(name) if declared.is_some_and(|regs| !regs.contains(name)) => {
+                Err(AsmError::UndeclaredRegister { pos: SourcePos::new(t.span, raw), name: name.clone() })
+            }
+            TokenKind::Ident(name) => Ok(name.clone()),
+            _ => Err(bad(t, "a register")),
+        }
+    };
+    let param = |i: usize| match operands[i].kind {
+        TokenKind::Int(val) => Ok(Param::Val(val)),
+        TokenKind::Ident(_) => reg(i).map(Param::Register),
+        _ => Err(bad(operands[i], "a register or an integer")),
+    };
+    let label = |i: usize| match &operands[i].kind {
+        TokenKind::Ident(name) => Ok(Target::new(name)),
+        _ => Err(bad(operands[i], "a label")),
+    };
+
+    let command = match mnemonic {
+        "inc" => Command::Inc(reg(0)?),
+        "dec" => Command::Dec(reg(0)?),
+        "mov" => Command::Move(reg(0)?, param(1)?),
+        "jnz" => Command::Jnz(param(0)?, param(1)?),
+        "add" => Command::Add(reg(0)?, param(1)?),
+        "sub" => Command::Sub(reg(0)?, param(1)?),
+        "mul" => Command::Mul(reg(0)?, param(1)?),
+        "div" => Command::Div(reg(0)?, param(1)?),
+        "cmp" => Command::Cmp(param(0)?, param(1)?),
+        "jmp" => Command::Jmp(label(0)?),
+        "jne" => Command::Jne(label(0)?),
+        "je" => Command::Je(label(0)?),
+        "jge" => Command::Jge(label(0)?),
+        "jg" => Command::Jg(label(0)?),
+        "jle" => Command::Jle(label(0)?),
+        "jl" => Command::Jl(label(0)?),
+        "call" => Command::Call(label(0)?),
+        "ret" => Command::Ret,
+        "end" => Command::End,
+        _ => Command::Msg(
+            (0..operands.len())
+                .map(|i| match &operands[i].kind {
+                    TokenKind::Str(text) => Ok(MsgArg::Text(text.clone())),
+                    TokenKind::Int(val) => Ok(MsgArg::Val(*val)),
+                    TokenKind::Ident(_) => reg(i).map(MsgArg::Register),
+                    _ => Err(bad(operands[i], "a string, a register or an integer")),
+                })
+                .collect::<Result<_, _>>()?,
+        ),
+    };
+    Ok(Some(Statement { command, span, operands: operands.iter().map(|t| t.span).collect() }))
+}
+
+fn end_of(line_no: usize, raw: &str) -> Span {
+    Span { line: line_no, column: raw.trim_end().chars().count() + 1, len: 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(line: &str) -> Command {
+        parse_line(1, line, None).unwrap().unwrap().command
+    }
+
+    fn error(line: &str) -> AsmError {
+        parse_line(1, line, None).unwrap_err()
+    }
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(command("  cmp 5, a  ; compare"), Command::Cmp(Param::Val(5), Param::Register("a".to_string())));
+        assert_eq!(command("loop:"), Command::Label("loop".to_string()));
+        assert_eq!(
+            command(r"msg 'a; \'b\'', c, 2 ; out"),
+            Command::Msg(vec![MsgArg::Text("a; 'b'".to_string()), MsgArg::Register("c".to_string()), MsgArg::Val(2)])
+        );
+        assert_eq!(parse_line(1, "   ; only a comment", None).unwrap(), None);
+
+        let stmt = parse_line(2, "jmp  far_away", None).unwrap().unwrap();
+        assert_eq!(stmt.operands, vec![Span { line: 2, column: 6, len: 8 }]);
+    }
+
+    #[test]
+    fn test_parse_named_registers() {
+        let reg = |name: &str| Param::Register(name.to_string());
+        assert_eq!(command("mov count, tmp1"), Command::Move("count".to_string(), reg("tmp1")));
+        assert_eq!(command("jnz count, -2"), Command::Jnz(reg("count"), Param::Val(-2)));
+
+        let declared = ["a".to_string(), "count".to_string()];
+        assert!(parse_line(1, "add count, a", Some(&declared)).is_ok());
+        let err = parse_line(1, "add count, tmp", Some(&declared)).unwrap_err();
+        assert!(matches!(err, AsmError::UndeclaredRegister { name, pos } if name == "tmp" && pos.column == 12));
+    }
+
+    #[test]
+    fn test_parse_line_errors() {
+        assert!(matches!(error("cmp 5, a,"), AsmError::UnexpectedToken { found, .. } if found == "end of line"));
+        assert!(matches!(error("mov a 5"), AsmError::UnexpectedToken { found, .. } if found == "5"));
+        assert!(matches!(error("jmp 'x'"), AsmError::BadOperand { expected: "a label", .. }));
+        assert!(matches!(error("l: inc a"), AsmError::UnexpectedToken { .. }));
+        assert!(matches!(error("inc a, b"), AsmError::OperandCount { expected: 1, found: 2, .. }));
+        assert!(matches!(error("msg 'a' b"), AsmError::UnexpectedToken { .. }));
+        assert!(matches!(error("msg 'a', :"), AsmError::UnexpectedToken { .. }));
+        assert!(matches!(error("mov 5, a"), AsmError::BadOperand { expected: "a register", .. }));
+        assert!(matches!(error("msg 'a"), AsmError::UnterminatedString { .. }));
+    }
+}
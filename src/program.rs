@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::decode::{self, Decoded};
+use crate::error::{AsmError, Diagnostic, SourcePos};
+use crate::lexer::Span;
+use crate::{analysis, link, parser, Command, Cpu};
+
+/// A loaded and linked program. It never changes once built, so it is
+/// shared between any number of `Cpu`s, each with its own registers.
+#[derive(Debug, Default)]
+pub struct Program {
+    pub(crate) code: Vec<Command>,
+    pub(crate) decoded: Decoded,
+    pub(crate) labels: HashMap<String, usize>,
+    pub(crate) positions: Vec<SourcePos>, // source of each instruction
+    warnings: Vec<Diagnostic>,
+    hash: u64,
+}
+
+impl Program {
+    /// Loads `source` with the default options. Returns every diagnostic
+    /// if there is an error; the warnings are kept in the program.
+    pub fn load(source: &str) -> Result<Program, Vec<Diagnostic>> {
+        let (program, diagnostics) = Program::build(source, None, false);
+        if diagnostics.iter().any(Diagnostic::is_error) {
+            Err(diagnostics)
+        } else {
+            Ok(program)
+        }
+    }
+
+    /// Parses every line, links the labels and decodes the result if no
+    /// error was found. The program holds whatever could be parsed, along
+    /// with the sorted diagnostics.
+    pub(crate) fn build(source: &str, declared: Option<&[String]>, warn_uninitialized: bool) -> (Program, Vec<Diagnostic>) {
+        let source: Vec<&str> = source.lines().collect();
+        let mut diagnostics = Vec::new();
+        let mut statements = Vec::new();
+
+        for (i, raw) in source.iter().enumerate() {
+            match parser::parse_line(i + 1, raw, declared) {
+                Ok(Some(stmt)) => statements.push(stmt),
+                Ok(None) => {}
+                Err(e) => diagnostics.push(Diagnostic::error(e)),
+            }
+        }
+
+        let (labels, link_diagnostics) = link::link(&mut statements, &source);
+        diagnostics.extend(link_diagnostics);
+        if !statements.iter().any(|stmt| stmt.command == Command::End) {
+            let line = source.len().max(1);
+            let raw = source.last().unwrap_or(&"");
+            diagnostics.push(Diagnostic::warning(AsmError::MissingEnd {
+                pos: SourcePos::new(Span { line, column: 1, len: 0 }, raw),
+            }));
+        }
+        let positions = statements.iter().map(|stmt| SourcePos::new(stmt.span, source[stmt.span.line - 1])).collect();
+        let code: Vec<Command> = statements.iter().map(|stmt| stmt.command.clone()).collect();
+        let mut decoded = Decoded::default();
+        if !diagnostics.iter().any(Diagnostic::is_error) {
+            if let Ok(d) = decode::decode(&code) {
+                decoded = d;
+            }
+        }
+        if warn_uninitialized {
+            for (address, r) in analysis::uninitialized_reads(&decoded) {
+                let stmt = &statements[address];
+                let name = &decoded.reg_names[r];
+                let line = source[stmt.span.line - 1];
+                let span = stmt
+                    .operands
+                    .iter()
+                    .copied()
+                    .find(|span| line.chars().skip(span.column - 1).take(span.len).eq(name.chars()))
+                    .unwrap_or(stmt.span);
+                diagnostics.push(Diagnostic::warning(AsmError::UninitializedRead {
+                    pos: SourcePos::new(span, line),
+                    name: name.clone(),
+                }));
+            }
+        }
+
+        diagnostics.sort_by_key(|d| (d.error.pos().line, d.error.pos().column));
+        let warnings = diagnostics.iter().filter(|d| !d.is_error()).cloned().collect();
+        let hash = hash_code(&code);
+        (Program { code, decoded, labels, positions, warnings, hash }, diagnostics)
+    }
+
+    /// A cpu running this program, with the default settings and every
+    /// register at 0. Cheap: the program itself isn't copied.
+    pub fn cpu(self: &Arc<Self>) -> Cpu {
+        Cpu::from_program(self.clone())
+    }
+
+    pub fn code(&self) -> &[Command] {
+        &self.code
+    }
+
+    /// Warnings found while loading.
+    pub fn warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+
+    pub fn label_address(&self, name: &str) -> Option<usize> {
+        self.labels.get(name).copied()
+    }
+
+    /// Source position of the instruction at `address`.
+    pub fn source_pos(&self, address: usize) -> Option<&SourcePos> {
+        self.positions.get(address)
+    }
+
+    /// Registers used by the program, in order of first use.
+    pub fn register_names(&self) -> &[String] {
+        &self.decoded.reg_names
+    }
+
+    /// Identity of the program, saved with a `CpuState`. Stable across
+    /// builds and machines; comments and layout don't change it.
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+}
+
+/// FNV-1a of the instructions as they are displayed, one per line.
+fn hash_code(code: &[Command]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for command in code {
+        for byte in command.to_string().bytes().chain([b'\n']) {
+            hash = (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GCD: &str = "loop:\ncmp b, 0\nje done\nmov t, a\nmov a, b\n\
+        ; a mod b, the remainder of t / b\nmov r, t\ndiv r, b\nmul r, b\nsub t, r\nmov b, t\njmp loop\ndone:\nmsg a\nend";
+
+    #[test]
+    fn test_program_executions() {
+        let program = Arc::new(Program::load(GCD).unwrap());
+        assert_eq!(program.register_names(), ["b", "t", "a", "r"]);
+        assert!(program.warnings().is_empty());
+        for (a, b, gcd) in [(12, 18, "6"), (17, 5, "1"), (0, 7, "7"), (270, 192, "6")] {
+            let mut cpu = program.cpu();
+            cpu.set_register_value("a", a).unwrap();
+            cpu.set_register_value("b", b).unwrap();
+            assert_eq!(cpu.run(), Ok(gcd.to_string()));
+        }
+
+        assert!(matches!(Program::load("jmp nowhere\nend"), Err(diagnostics) if diagnostics.len() == 1));
+        assert_eq!(Program::load("inc a").unwrap().warnings().len(), 1);
+        assert_eq!(program.hash(), Program::load(&GCD.replace("; a mod b", ";")).unwrap().hash());
+        assert_ne!(program.hash(), Program::load(&GCD.replace("msg a", "msg b")).unwrap().hash());
+    }
+}
@@ -0,0 +1,60 @@
+use std::fmt;
+
+use crate::error::{Diagnostic, RunError};
+
+/// Why an execution stopped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Termination {
+    End,                        // reached `end`
+    LoadFailed(Vec<Diagnostic>), // the program has errors, nothing ran
+    Failed(RunError),           // no end, fault, budget, cancel or timeout
+}
+
+impl fmt::Display for Termination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Termination::End => write!(f, "end"),
+            Termination::LoadFailed(diagnostics) => {
+                let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+                write!(f, "load failed with {} error(s)", errors)
+            }
+            Termination::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Everything known about an execution, see `AssemblerInterpreter::execute`
+/// and `Cpu::execute`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExecutionReport {
+    pub output: String,                 // msg output, partial if the run failed
+    pub termination: Termination,
+    pub registers: Vec<(String, i64)>,  // final values, in order of first use
+    pub steps: u64,                     // instructions executed
+    pub max_call_depth: usize,          // deepest call stack reached
+}
+
+impl ExecutionReport {
+    pub fn load_failed(diagnostics: Vec<Diagnostic>) -> Self {
+        ExecutionReport {
+            output: String::new(),
+            termination: Termination::LoadFailed(diagnostics),
+            registers: Vec::new(),
+            steps: 0,
+            max_call_depth: 0,
+        }
+    }
+
+    /// True if the program reached `end`.
+    pub fn succeeded(&self) -> bool {
+        self.termination == Termination::End
+    }
+
+    /// The output, as `AssemblerInterpreter::interpret` returns it.
+    pub fn into_result(self) -> Option<String> {
+        match self.termination {
+            Termination::End => Some(self.output),
+            _ => None,
+        }
+    }
+}
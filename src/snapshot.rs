@@ -0,0 +1,121 @@
+use crate::Comparison;
+
+/// Checkpoints kept by the execution history before it thins them out.
+const MAX_CHECKPOINTS: usize = 1024;
+/// Pinned checkpoints kept by the execution history. The history before
+/// the oldest one is forgotten.
+const MAX_PINNED: usize = 64;
+
+/// The execution state of a `Cpu` between two instructions, see
+/// `Cpu::snapshot`. The program and the settings are not part of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub(crate) regs: Vec<i64>,
+    pub(crate) written: Vec<bool>,
+    pub(crate) compare: Option<Comparison>,
+    pub(crate) sub_calls: Vec<usize>,
+    pub(crate) ip: usize,
+    pub(crate) steps: u64,
+    pub(crate) max_depth: usize,
+    pub(crate) output_len: usize, // the output only grows, restoring truncates it
+}
+
+impl Snapshot {
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    /// Instructions executed when the snapshot was taken.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+}
+
+/// Checkpoints of a run, taken every `interval` steps, from which any
+/// earlier state is rebuilt by re-executing the program. When there are
+/// too many, every other one is dropped and the interval doubles, so the
+/// memory stays bounded however long the run is. Past `MAX_PINNED` pinned
+/// checkpoints, the history starts at the oldest one kept.
+#[derive(Debug, Clone)]
+pub(crate) struct History {
+    interval: u64,
+    checkpoints: Vec<(Snapshot, bool)>, // sorted by steps, true for the pinned ones
+}
+
+impl History {
+    pub(crate) fn new(interval: u64) -> Self {
+        History { interval: interval.max(1), checkpoints: Vec::new() }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.checkpoints.clear();
+    }
+
+    /// Whether a checkpoint should be taken before executing step `steps`.
+    #[inline]
+    pub(crate) fn due(&self, steps: u64) -> bool {
+        steps.is_multiple_of(self.interval) && self.checkpoints.last().is_none_or(|(s, _)| s.steps < steps)
+    }
+
+    /// Adds a checkpoint after the others. A pinned one records a state
+    /// that re-execution can't rebuild, e.g. a register changed by hand, so
+    /// it replaces the later checkpoints and isn't thinned out.
+    pub(crate) fn record(&mut self, snapshot: Snapshot, pinned: bool) {
+        self.checkpoints.retain(|(s, _)| s.steps < snapshot.steps);
+        self.checkpoints.push((snapshot, pinned));
+        let mut pins = self.checkpoints.iter().enumerate().filter(|(_, (_, pinned))| *pinned).map(|(i, _)| i);
+        if let Some(oldest) = pins.nth_back(MAX_PINNED - 1) {
+            if oldest > 0 {
+                self.checkpoints.drain(..oldest);
+            }
+        }
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            self.interval = self.interval.saturating_mul(2);
+            let interval = self.interval;
+            self.checkpoints.retain(|(s, pinned)| *pinned || s.steps.is_multiple_of(interval));
+        }
+    }
+
+    /// Drops the checkpoints after `steps` and returns the last one left,
+    /// or the first one if they all were after it.
+    pub(crate) fn rewind(&mut self, steps: u64) -> Option<Snapshot> {
+        let kept = self.checkpoints.iter().take_while(|(s, _)| s.steps <= steps).count().max(1);
+        self.checkpoints.truncate(kept);
+        self.checkpoints.last().map(|(s, _)| s.clone())
+    }
+
+    /// Steps of the checkpoints before `steps`, latest first.
+    pub(crate) fn starts_before(&self, steps: u64) -> Vec<u64> {
+        self.checkpoints.iter().rev().map(|(s, _)| s.steps).filter(|&s| s < steps).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(steps: u64) -> Snapshot {
+        Snapshot {
+            regs: Vec::new(),
+            written: Vec::new(),
+            compare: None,
+            sub_calls: Vec::new(),
+            ip: 0,
+            steps,
+            max_depth: 0,
+            output_len: 0,
+        }
+    }
+
+    #[test]
+    fn test_history_bounds() {
+        let mut history = History::new(1);
+        for steps in 0..10_000 {
+            history.record(snapshot(steps), steps % 10 == 0);
+        }
+        assert!(history.checkpoints.len() <= MAX_CHECKPOINTS);
+        assert_eq!(history.checkpoints.iter().filter(|(_, pinned)| *pinned).count(), MAX_PINNED);
+        assert_eq!(history.checkpoints[0].0.steps, 10_000 - 10 * MAX_PINNED as u64);
+        assert_eq!(history.rewind(0).map(|s| s.steps), Some(10_000 - 10 * MAX_PINNED as u64));
+    }
+}
@@ -0,0 +1,86 @@
+use serde_json::{json, Map, Value};
+
+use crate::Comparison;
+
+/// Version of the JSON written by `CpuState::to_json`.
+const FORMAT_VERSION: u64 = 1;
+
+/// The complete execution state of a `Cpu`, to resume it later or on
+/// another machine, or to inspect a crash. See `Cpu::save_state`.
+///
+/// The settings (arithmetic mode, fault handler...) are not saved: resume
+/// with the same ones to get the same results.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuState {
+    pub program_hash: u64,             // see `Program::hash`
+    pub registers: Vec<(String, i64)>, // any order, the missing ones are 0
+    pub written: Option<Vec<String>>,  // registers written so far, only kept under `UninitializedReads::Fault`
+    pub comparison: Option<Comparison>,
+    pub call_stack: Vec<usize>,        // addresses of the pending calls, innermost last
+    pub ip: usize,
+    pub steps: u64,
+    pub max_call_depth: usize,         // deepest call stack reached
+    pub output: String,                // msg output so far, unless an output sink is set
+}
+
+impl CpuState {
+    /// `{ "version": 1, "program": "<hash in hex>", "registers": { "a": 1 }, ... }`
+    pub fn to_json(&self) -> Value {
+        let registers: Map<String, Value> = self.registers.iter().map(|(name, val)| (name.clone(), json!(val))).collect();
+        json!({
+            "version": FORMAT_VERSION,
+            "program": format!("{:016x}", self.program_hash),
+            "registers": registers,
+            "written": self.written,
+            "comparison": self.comparison.as_ref().map(Comparison::name),
+            "call_stack": self.call_stack,
+            "ip": self.ip,
+            "steps": self.steps,
+            "max_call_depth": self.max_call_depth,
+            "output": self.output,
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self, String> {
+        let field = |name: &str| value.get(name).ok_or_else(|| format!("missing `{}`", name));
+        let bad = |name: &str| format!("bad `{}`", name);
+        let version = field("version")?.as_u64().ok_or_else(|| bad("version"))?;
+        if version != FORMAT_VERSION {
+            return Err(format!("unsupported state version {}", version));
+        }
+        let uint = |name: &str| field(name)?.as_u64().ok_or_else(|| bad(name));
+        let program = field("program")?.as_str().ok_or_else(|| bad("program"))?;
+        let registers = field("registers")?.as_object().ok_or_else(|| bad("registers"))?;
+        let call_stack = field("call_stack")?.as_array().ok_or_else(|| bad("call_stack"))?;
+        Ok(CpuState {
+            program_hash: u64::from_str_radix(program, 16).map_err(|_| bad("program"))?,
+            registers: registers
+                .iter()
+                .map(|(name, val)| val.as_i64().map(|val| (name.clone(), val)).ok_or_else(|| bad("registers")))
+                .collect::<Result<_, _>>()?,
+            written: match field("written")? {
+                Value::Null => None,
+                Value::Array(written) => Some(
+                    written
+                        .iter()
+                        .map(|name| name.as_str().map(str::to_string).ok_or_else(|| bad("written")))
+                        .collect::<Result<_, _>>()?,
+                ),
+                _ => return Err(bad("written")),
+            },
+            comparison: match field("comparison")? {
+                Value::Null => None,
+                Value::String(name) => Some(Comparison::from_name(name).ok_or_else(|| bad("comparison"))?),
+                _ => return Err(bad("comparison")),
+            },
+            call_stack: call_stack
+                .iter()
+                .map(|address| address.as_u64().map(|a| a as usize).ok_or_else(|| bad("call_stack")))
+                .collect::<Result<_, _>>()?,
+            ip: uint("ip")? as usize,
+            steps: uint("steps")?,
+            max_call_depth: uint("max_call_depth")? as usize,
+            output: field("output")?.as_str().ok_or_else(|| bad("output"))?.to_string(),
+        })
+    }
+}
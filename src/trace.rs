@@ -0,0 +1,94 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use serde_json::{json, Map, Value};
+
+use crate::{Command, Comparison};
+
+/// One executed instruction, see `Cpu::set_tracer`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEvent<'a> {
+    pub step: u64,                    // 1 for the first instruction of the run
+    pub address: usize,
+    pub line: Option<usize>,          // source line of the instruction
+    pub command: &'a Command,
+    pub changes: Vec<(&'a str, i64)>, // registers written with a new value
+    pub comparison: Option<&'a Comparison>,
+    pub depth: usize,                 // pending calls after the instruction
+}
+
+impl TraceEvent<'_> {
+    /// The event as a JSON object, e.g.
+    /// `{"step":3,"address":2,"line":3,"command":"inc a","changes":{"a":6},"comparison":null,"depth":0}`.
+    pub fn to_json(&self) -> Value {
+        let changes: Map<String, Value> = self.changes.iter().map(|(name, val)| (name.to_string(), json!(val))).collect();
+        json!({
+            "step": self.step,
+            "address": self.address,
+            "line": self.line,
+            "command": self.command.to_string(),
+            "changes": changes,
+            "comparison": self.comparison.map(Comparison::name),
+            "depth": self.depth,
+        })
+    }
+}
+
+/// Receives every instruction a cpu executes.
+pub trait Tracer {
+    fn record(&mut self, event: &TraceEvent);
+}
+
+/// A tracer shared between the cpu and its owner.
+pub type SharedTracer = Arc<Mutex<dyn Tracer + Send>>;
+
+/// Writes each event as one line of JSON (JSON Lines). Write errors are
+/// ignored.
+#[derive(Debug)]
+pub struct JsonLinesTracer<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> JsonLinesTracer<W> {
+    pub fn new(writer: W) -> Self {
+        JsonLinesTracer { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> Tracer for JsonLinesTracer<W> {
+    fn record(&mut self, event: &TraceEvent) {
+        let _ = writeln!(self.writer, "{}", event.to_json());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cpu;
+
+    #[test]
+    fn test_json_lines_tracer() {
+        let tracer = Arc::new(Mutex::new(JsonLinesTracer::new(Vec::new())));
+        let mut cpu = Cpu::new();
+        cpu.load_code("mov a, 5\ncall f\nend\nf:\n  cmp a, 2\n  ret").unwrap();
+        cpu.set_tracer(Some(tracer.clone()));
+        cpu.run().unwrap();
+
+        let lines = String::from_utf8(tracer.lock().unwrap().writer.clone()).unwrap();
+        let lines: Vec<&str> = lines.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(
+            lines[0],
+            r#"{"address":0,"changes":{"a":5},"command":"mov a, 5","comparison":null,"depth":0,"line":1,"step":1}"#
+        );
+        assert_eq!(
+            lines[3],
+            r#"{"address":4,"changes":{},"command":"cmp a, 2","comparison":"greater","depth":1,"line":5,"step":4}"#
+        );
+        assert!(lines[4].contains(r#""command":"ret""#) && lines[4].contains(r#""depth":0"#));
+    }
+}